@@ -6,12 +6,16 @@ use super::thread::Handler;
 use super::evented::{RcEventSourceTrait, RcEventSource, EventSourceTrait};
 use super::thread::RcHandlerShared;
 use super::mail;
+use super::overflow;
+use super::sim::SimHost;
+use super::trace::{self, Tracer};
 use super::mio::EventLoop;
 use super::mio_orig::{Token, EventSet};
 
 use context::{Context, Stack};
 use slab;
 use libc;
+use time::{SteadyTime, Duration};
 
 use std::any::Any;
 use std::io;
@@ -23,6 +27,43 @@ use std::boxed::FnBox;
 use std::mem;
 use std::panic;
 use std::ptr;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+/// Source of process-unique coroutine ids (see `Coroutine::uid`)
+static NEXT_UID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Pattern coroutine stacks are painted with (`0x5a5a...`)
+const STACK_PAINT: usize = (!0 / 0xff) * 0x5a;
+
+/// Lowest address of the stack that can be painted and scanned
+///
+/// Just above the protection page at the bottom of `Stack`.
+fn stack_usable_start(stack: &Stack) -> usize {
+    stack.start() as usize + overflow::page_size()
+}
+
+/// Fill the stack with `STACK_PAINT`.
+unsafe fn stack_paint(stack: &Stack) {
+    let end = stack.end() as *mut usize;
+    let mut ptr = stack_usable_start(stack) as *mut usize;
+    while ptr < end {
+        *ptr = STACK_PAINT;
+        ptr = ptr.offset(1);
+    }
+}
+
+/// Find how much of a painted stack was ever used.
+///
+/// Stack grows down, so the lowest overwritten word marks the high-water
+/// mark.
+unsafe fn stack_usage(stack: &Stack) -> usize {
+    let end = stack.end();
+    let mut ptr = stack_usable_start(stack) as *const usize;
+    while ptr < end && *ptr == STACK_PAINT {
+        ptr = ptr.offset(1);
+    }
+    (end as usize).saturating_sub(ptr as usize)
+}
 
 /// Id of a Coroutine used to enumerate them
 ///
@@ -146,6 +187,35 @@ pub struct Coroutine {
     /// Current coroutine Id
     pub id: Id,
 
+    /// Process-unique coroutine id, preserved across migrations
+    pub uid: usize,
+
+    /// Name of the coroutine (see `mioco::set_name()`)
+    pub name: Option<Arc<String>>,
+
+    /// Accumulated wall-clock time spent running
+    pub run_time: Duration,
+
+    /// Accumulated time spent waiting to be resumed after becoming ready
+    pub sched_latency: Duration,
+
+    /// When coroutine became `Ready`
+    ready_at: Option<SteadyTime>,
+
+    /// Measure `run_time` and `sched_latency` (see
+    /// `Config::set_timing_stats()`)
+    timing_stats: bool,
+
+    /// Tell the stack overflow handler about this coroutine (see
+    /// `Config::set_stack_overflow_reporting()`)
+    overflow_reporting: bool,
+
+    /// Is the stack painted (see `Config::set_stack_usage_tracking()`)
+    stack_painted: bool,
+
+    /// Stack high-water mark, measured on finish
+    pub stack_usage: Option<usize>,
+
     /// Coroutine stack
     stack: Stack,
 
@@ -171,6 +241,9 @@ pub struct Coroutine {
 
     /// if this coroutine should catch panics
     catch_panics: bool,
+
+    /// Simulated host sockets are created on (see `sim::set_current_host()`)
+    pub sim_host: Option<SimHost>,
 }
 
 impl Coroutine {
@@ -183,7 +256,15 @@ impl Coroutine {
         where F: FnOnce() -> io::Result<()> + Send + 'static
     {
         trace!("Coroutine: spawning");
-        let stack_size = handler_shared.borrow().stack_size;
+        let (stack_size, stack_usage_tracking, timing_stats, overflow_reporting, sim_host) = {
+            let shared = handler_shared.borrow();
+            let config = &shared.config;
+            (config.stack_size,
+             config.stack_usage_tracking,
+             config.timing_stats,
+             config.overflow_reporting,
+             config.sim_host.clone())
+        };
 
         let id = {
             let coroutines = &mut handler_shared.borrow_mut().coroutines;
@@ -197,6 +278,19 @@ impl Coroutine {
                           let coroutine = Coroutine {
                               state: State::Ready,
                               id: id,
+                              uid: NEXT_UID.fetch_add(1, Ordering::Relaxed),
+                              name: None,
+                              run_time: Duration::zero(),
+                              sched_latency: Duration::zero(),
+                              ready_at: if timing_stats {
+                                  Some(SteadyTime::now())
+                              } else {
+                                  None
+                              },
+                              timing_stats: timing_stats,
+                              overflow_reporting: overflow_reporting,
+                              stack_painted: false,
+                              stack_usage: None,
                               last_event: Event {
                                   rw: RW::read(),
                                   id: EventSourceId(0),
@@ -213,6 +307,7 @@ impl Coroutine {
                               user_data: inherited_user_data.clone(),
                               inherited_user_data: inherited_user_data,
                               catch_panics: catch_panics,
+                              sim_host: sim_host,
                           };
 
                           CoroutineSlabHandle::new(Rc::new(RefCell::new(coroutine)))
@@ -225,6 +320,10 @@ impl Coroutine {
 
         coroutine_rc.borrow_mut().self_rc = Some(coroutine_rc.clone());
 
+        if let Some((tracer, info)) = coroutine_rc.borrow().tracer() {
+            tracer.spawned(info);
+        }
+
         let coroutine_ptr = {
             // The things we do for borrowck...
             let coroutine_ptr = {
@@ -250,6 +349,18 @@ impl Coroutine {
                 });
 
                 let coroutine: &mut Coroutine = unsafe { mem::transmute(arg) };
+                if coroutine.stack_painted {
+                    let used = unsafe { stack_usage(&coroutine.stack) };
+                    debug!("Coroutine({}): used {} bytes of stack",
+                           coroutine.uid,
+                           used);
+                    coroutine.stack_usage = Some(used);
+                    let name = coroutine.name
+                                        .as_ref()
+                                        .map(|name| name.as_str())
+                                        .unwrap_or("<unnamed>");
+                    coroutine.handler_shared().stats().record_stack_usage(name, used);
+                }
                 coroutine.blocked_on.clear();
                 coroutine.self_rc = None;
 
@@ -299,6 +410,14 @@ impl Coroutine {
                     }
                 }
 
+                if let Some((tracer, info)) = coroutine.tracer() {
+                    let status = match coroutine.state {
+                        State::Finished(ref status) => status.clone(),
+                        _ => ExitStatus::Panic,
+                    };
+                    tracer.finished(info, &status);
+                }
+
                 unsafe {
                     let handler = coroutine.handler_shared.as_ref().unwrap().borrow();
                     mem::transmute(&handler.context as *const Context)
@@ -312,9 +431,15 @@ impl Coroutine {
             let Coroutine {
                 ref mut stack,
                 ref mut context,
+                ref mut stack_painted,
                 ..
             } = *coroutine_rc.borrow_mut();
 
+            if stack_usage_tracking {
+                unsafe { stack_paint(stack) };
+                *stack_painted = true;
+            }
+
             context.init_with(init_fn, coroutine_ptr as usize, ptr::null_mut(), stack);
         }
 
@@ -328,10 +453,58 @@ impl Coroutine {
                 self.inherited_user_data.clone(),
                 f,
                 self.catch_panics);
+            child.borrow_mut().sim_host = self.sim_host.clone();
             self.children_to_start.push(child.clone());
             child
         }
 
+    /// Tracer of the thread the coroutine is attached to, along with the
+    /// details of the current lifecycle event.
+    pub fn tracer(&self) -> Option<(Arc<Box<Tracer>>, trace::Info)> {
+        let handler_shared = match self.handler_shared {
+            Some(ref handler_shared) => handler_shared.borrow(),
+            None => return None,
+        };
+
+        let res = handler_shared.config.tracer.as_ref().map(|tracer| {
+            (tracer.clone(),
+             trace::Info {
+                coroutine_id: self.uid,
+                thread_id: handler_shared.thread_id(),
+                time: SteadyTime::now(),
+            })
+        });
+        res
+    }
+
+    /// Size of the coroutine stack, in bytes
+    pub fn stack_size(&self) -> usize {
+        self.stack.end() as usize - self.stack.start() as usize
+    }
+
+    /// Details for the stack overflow handler, if enabled
+    ///
+    /// The guard range is only the protection page `Stack` reserves at the
+    /// bottom of the stack.
+    pub fn overflow_current(&self) -> Option<overflow::Current> {
+        if !self.overflow_reporting {
+            return None;
+        }
+        let (guard_start, guard_end) = overflow::guard_range(self.stack.start() as usize);
+        let (name_ptr, name_len) = match self.name {
+            Some(ref name) => (name.as_ptr() as usize, name.len()),
+            None => (0, 0),
+        };
+        Some(overflow::Current {
+            guard_start: guard_start,
+            guard_end: guard_end,
+            uid: self.uid,
+            stack_size: self.stack_size(),
+            name_ptr: name_ptr,
+            name_len: name_len,
+        })
+    }
+
     pub fn handler_shared(&self) -> cell::Ref<HandlerShared> {
         self.handler_shared.as_ref().unwrap().borrow()
     }
@@ -354,8 +527,12 @@ impl Coroutine {
 
     pub fn unblock(&mut self, event_loop: &mut EventLoop<Handler>, event : Event) {
         self.state = coroutine::State::Ready;
+        self.mark_ready();
         self.last_event = event;
 
+        if let Some(io) = self.blocked_on.get(self.last_event.id) {
+            io.on_resume();
+        }
         self.deregister_all(event_loop);
     }
     pub fn finish(&mut self) {
@@ -364,6 +541,13 @@ impl Coroutine {
 
     pub fn unblock_after_yield(&mut self) {
         self.state = coroutine::State::Ready;
+        self.mark_ready();
+    }
+
+    fn mark_ready(&mut self) {
+        if self.timing_stats {
+            self.ready_at = Some(SteadyTime::now());
+        }
     }
 
     pub fn state(&self) -> &State {
@@ -550,15 +734,25 @@ pub fn jump_in(coroutine: &RefCell<Coroutine>) {
         *co = &mut *coroutine.borrow_mut() as *mut Coroutine;
         prev
     });
+    let prev_overflow = coroutine.borrow().overflow_current().map(overflow::swap_current);
 
     {
-        let ref mut state = coroutine.borrow_mut().state;
-        match *state {
-            State::Ready => {
-                *state = State::Running;
+        let mut co = coroutine.borrow_mut();
+        {
+            let ref mut state = co.state;
+            match *state {
+                State::Ready => {
+                    *state = State::Running;
+                }
+                State::Finished(ExitStatus::Killed) => {}
+                ref state => panic!("coroutine_jump_in: wrong state {:?}", state),
             }
-            State::Finished(ExitStatus::Killed) => {}
-            ref state => panic!("coroutine_jump_in: wrong state {:?}", state),
+        }
+
+        if let Some(ready_at) = co.ready_at.take() {
+            let latency = SteadyTime::now() - ready_at;
+            co.sched_latency = co.sched_latency + latency;
+            co.handler_shared().stats().sched_latency().record_duration(latency);
         }
     }
 
@@ -578,18 +772,48 @@ pub fn jump_in(coroutine: &RefCell<Coroutine>) {
         }
     };
 
+    let started = if coroutine.borrow().timing_stats {
+        Some(SteadyTime::now())
+    } else {
+        None
+    };
     Context::swap(unsafe { &mut *context_out }, unsafe { &*context_in });
     TL_CURRENT_COROUTINE.with(|co| {
         *co.borrow_mut() = prev;
     });
+    if let Some(prev_overflow) = prev_overflow {
+        overflow::swap_current(prev_overflow);
+    }
+
+    {
+        if let Some(started) = started {
+            let mut co = coroutine.borrow_mut();
+            co.run_time = co.run_time + (SteadyTime::now() - started);
+            if let State::Finished(_) = co.state {
+                co.handler_shared().stats().run_time().record_duration(co.run_time);
+            }
+        }
+    }
 }
 
 /// Block coroutine execution, jumping out of it
 // TODO: Make part of the Coroutine, using rc_self
 pub fn jump_out(coroutine: &RefCell<Coroutine>) {
     {
-        let state = &coroutine.borrow().state;
-        debug_assert!(state.is_blocked() || state.is_yielding());
+        let co = coroutine.borrow();
+        debug_assert!(co.state.is_blocked() || co.state.is_yielding());
+
+        if let Some((tracer, info)) = co.tracer() {
+            if co.state.is_yielding() {
+                tracer.yielded(info);
+            } else {
+                let blocked_on : Vec<_> = co.blocked_on
+                                            .iter()
+                                            .map(|io| (io.id(), io.blocked_on()))
+                                            .collect();
+                tracer.blocked(info, &blocked_on);
+            }
+        }
     }
 
     // See `resume()` for unsafe comment
@@ -1,6 +1,7 @@
 use super::{RW, EventSourceId, coroutine};
 use super::thread::Handler;
-use super::{tl_coroutine_current};
+use super::{tl_coroutine_current, in_coroutine};
+use super::fault::{Faults, Fault, FaultInjector};
 use super::{token_from_ids};
 use super::mio_orig;
 
@@ -8,6 +9,7 @@ use super::mio_orig::{EventLoop, Token, EventSet};
 
 use std::io;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::cell::{RefCell, Ref, RefMut};
 #[cfg(not(windows))]
 use std::os::unix::io::{RawFd, FromRawFd, AsRawFd};
@@ -90,42 +92,78 @@ pub trait EventedImpl {
 // `Evented` structs after return. `Rc` is used only when coroutine
 // is blocked - meaning it is not using it.
 unsafe impl<T> Send for MioAdapter<T>
-where T: mio_orig::Evented + Send {}
+where T: EventSourceTrait + Send {}
 
 /// Adapt raw `mio` type to mioco `Evented` requirements.
 ///
+/// Any `EventSourceTrait` implementation can be wrapped too; with the `sim`
+/// feature, mioco sockets wrap a `Backend` choosing between a `mio` socket
+/// and a simulated one.
+///
 /// See source of `src/tcp.rs` for example of usage.
-pub struct MioAdapter<MT>(RcEventSource<MT>);
+pub struct MioAdapter<MT> {
+    rc: RcEventSource<MT>,
+    faults: Option<Arc<FaultInjector>>,
+}
 
 impl<MT> MioAdapter<MT>
-where MT : mio_orig::Evented+'static {
-    /// Create `MioAdapter` from raw mio type.
+where MT : EventSourceTrait+'static {
+    /// Create `MioAdapter` from raw mio type (or other event source).
+    ///
+    /// Inside a coroutine, fault injection configured for the mioco
+    /// instance (see `Config::set_faults()`) applies to it.
     pub fn new(mio_type : MT) -> Self {
-        MioAdapter(RcEventSource::new(mio_type))
+        let faults = if in_coroutine() {
+            tl_coroutine_current().handler_shared().config.faults.clone()
+        } else {
+            None
+        };
+
+        MioAdapter {
+            rc: RcEventSource::new(mio_type),
+            faults: faults,
+        }
     }
+
+    /// Inject IO faults into this event source.
+    ///
+    /// `None` disables fault injection.
+    pub fn set_faults(&mut self, faults: Option<Faults>) {
+        self.faults = faults.map(|faults| Arc::new(FaultInjector::new(faults)));
+    }
+}
+
+fn injected(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, "injected fault")
 }
 
 impl<MT> EventedImpl for MioAdapter<MT>
-where MT : mio_orig::Evented+'static {
+where MT : EventSourceTrait+'static {
     type Raw = MT;
 
     fn shared(&self) -> &RcEventSource<Self::Raw> {
-        &self.0
+        &self.rc
     }
 }
 
 impl<MT> MioAdapter<MT>
-where MT : mio_orig::Evented+'static + mio_orig::TryRead {
+where MT : EventSourceTrait+'static + mio_orig::TryRead {
     /// Try reading data into a buffer.
     ///
     /// This will not block.
     pub fn try_read(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
-        self.shared().io_mut().try_read(buf)
+        let len = match self.faults.as_ref().and_then(|f| f.read_fault(buf.len())) {
+            None => buf.len(),
+            Some(Fault::Partial(len)) => len,
+            Some(Fault::WouldBlock) => return Ok(None),
+            Some(Fault::Error(kind)) => return Err(injected(kind)),
+        };
+        self.shared().io_mut().try_read(&mut buf[..len])
     }
 }
 
 impl<MT> io::Read for MioAdapter<MT>
-where MT : mio_orig::Evented+'static + mio_orig::TryRead {
+where MT : EventSourceTrait+'static + mio_orig::TryRead {
     /// Block on read.
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         loop {
@@ -143,17 +181,23 @@ where MT : mio_orig::Evented+'static + mio_orig::TryRead {
 }
 
 impl<MT> MioAdapter<MT>
-where MT : mio_orig::Evented+'static + mio_orig::TryWrite {
+where MT : EventSourceTrait+'static + mio_orig::TryWrite {
     /// Try writing a data from the buffer.
     ///
     /// This will not block.
     pub fn try_write(&self, buf: &[u8]) -> io::Result<Option<usize>> {
-        self.shared().io_mut().try_write(buf)
+        let len = match self.faults.as_ref().and_then(|f| f.write_fault(buf.len())) {
+            None => buf.len(),
+            Some(Fault::Partial(len)) => len,
+            Some(Fault::WouldBlock) => return Ok(None),
+            Some(Fault::Error(kind)) => return Err(injected(kind)),
+        };
+        self.shared().io_mut().try_write(&buf[..len])
     }
 }
 
 impl<MT> io::Write for MioAdapter<MT>
-where MT : mio_orig::Evented+'static + mio_orig::TryWrite {
+where MT : EventSourceTrait+'static + mio_orig::TryWrite {
     /// Block on write.
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         loop {
@@ -176,8 +220,8 @@ where MT : mio_orig::Evented+'static + mio_orig::TryWrite {
 }
 
 impl<MT, O> MioAdapter<MT>
-where MT : mio_orig::Evented+'static + mio_orig::TryAccept<Output=O>,
-      O : mio_orig::Evented+'static
+where MT : EventSourceTrait+'static + mio_orig::TryAccept<Output=O>,
+      O : EventSourceTrait+'static
 {
     /// Attempt to accept a pending connection.
     ///
@@ -191,8 +235,8 @@ where MT : mio_orig::Evented+'static + mio_orig::TryAccept<Output=O>,
 }
 
 impl<MT, O> MioAdapter<MT>
-where MT : mio_orig::Evented+'static + mio_orig::TryAccept<Output=O>,
-      O : mio_orig::Evented+'static
+where MT : EventSourceTrait+'static + mio_orig::TryAccept<Output=O>,
+      O : EventSourceTrait+'static
 {
     /// Block on accepting a connection.
     pub fn accept(&self) -> io::Result<MioAdapter<O>> {
@@ -214,15 +258,15 @@ where MT : mio_orig::Evented+'static + mio_orig::TryAccept<Output=O>,
 //    type Output = MioAdapter<MT::Output>;
 #[cfg(not(windows))]
 impl<MT> FromRawFd for MioAdapter<MT>
-where MT : mio_orig::Evented+'static + FromRawFd {
+where MT : EventSourceTrait+'static + FromRawFd {
     unsafe fn from_raw_fd(fd: RawFd) -> Self {
-        MioAdapter(RcEventSource::new(MT::from_raw_fd(fd)))
+        MioAdapter::new(MT::from_raw_fd(fd))
     }
 }
 
 #[cfg(not(windows))]
 impl<MT> AsRawFd for MioAdapter<MT>
-where MT : mio_orig::Evented+'static + AsRawFd {
+where MT : EventSourceTrait+'static + AsRawFd {
     fn as_raw_fd(&self) -> RawFd {
         self.shared().0.borrow_mut().io.as_raw_fd()
     }
@@ -266,6 +310,11 @@ pub trait EventSourceTrait {
 
     /// Should the coroutine be resumed on event for this `EventSource<Self>`
     fn should_resume(&self) -> bool;
+
+    /// The coroutine is being resumed because of this event source
+    ///
+    /// Called before all event sources of the coroutine are deregistered.
+    fn on_resume(&self) {}
 }
 
 impl<T> EventSourceTrait for T where T: mio_orig::Evented
@@ -292,6 +341,140 @@ impl<T> EventSourceTrait for T where T: mio_orig::Evented
     }
 }
 
+/// Raw IO behind mioco sockets
+///
+/// Either a native `mio` type, or its simulated counterpart (see `sim`).
+/// Implements the traits `MioAdapter` needs by dispatching to the variant
+/// in use.
+#[cfg(feature = "sim")]
+pub enum Backend<M, S> {
+    /// Real socket
+    Mio(M),
+    /// Simulated socket
+    Sim(S),
+}
+
+#[cfg(feature = "sim")]
+impl<M, S> EventSourceTrait for Backend<M, S>
+    where M: EventSourceTrait,
+          S: EventSourceTrait
+{
+    fn register(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        match *self {
+            Backend::Mio(ref io) => io.register(event_loop, token, interest),
+            Backend::Sim(ref io) => io.register(event_loop, token, interest),
+        }
+    }
+
+    fn reregister(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        match *self {
+            Backend::Mio(ref io) => io.reregister(event_loop, token, interest),
+            Backend::Sim(ref io) => io.reregister(event_loop, token, interest),
+        }
+    }
+
+    fn deregister(&self, event_loop: &mut EventLoop<Handler>, token: Token) {
+        match *self {
+            Backend::Mio(ref io) => io.deregister(event_loop, token),
+            Backend::Sim(ref io) => io.deregister(event_loop, token),
+        }
+    }
+
+    fn should_resume(&self) -> bool {
+        match *self {
+            Backend::Mio(ref io) => io.should_resume(),
+            Backend::Sim(ref io) => io.should_resume(),
+        }
+    }
+
+    fn on_resume(&self) {
+        match *self {
+            Backend::Mio(ref io) => io.on_resume(),
+            Backend::Sim(ref io) => io.on_resume(),
+        }
+    }
+}
+
+#[cfg(feature = "sim")]
+impl<M, S> io::Read for Backend<M, S>
+    where M: io::Read,
+          S: io::Read
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Backend::Mio(ref mut io) => io.read(buf),
+            Backend::Sim(ref mut io) => io.read(buf),
+        }
+    }
+}
+
+#[cfg(feature = "sim")]
+impl<M, S> io::Write for Backend<M, S>
+    where M: io::Write,
+          S: io::Write
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Backend::Mio(ref mut io) => io.write(buf),
+            Backend::Sim(ref mut io) => io.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Backend::Mio(ref mut io) => io.flush(),
+            Backend::Sim(ref mut io) => io.flush(),
+        }
+    }
+}
+
+#[cfg(feature = "sim")]
+impl<M, S> mio_orig::TryAccept for Backend<M, S>
+    where M: mio_orig::TryAccept,
+          S: mio_orig::TryAccept
+{
+    type Output = Backend<M::Output, S::Output>;
+
+    fn accept(&self) -> io::Result<Option<Self::Output>> {
+        match *self {
+            Backend::Mio(ref io) => {
+                mio_orig::TryAccept::accept(io).map(|t| t.map(Backend::Mio))
+            }
+            Backend::Sim(ref io) => {
+                mio_orig::TryAccept::accept(io).map(|t| t.map(Backend::Sim))
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "sim", not(windows)))]
+impl<M, S> FromRawFd for Backend<M, S>
+    where M: FromRawFd
+{
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Backend::Mio(M::from_raw_fd(fd))
+    }
+}
+
+/// Simulated sockets have no descriptor, and report `-1`.
+#[cfg(all(feature = "sim", not(windows)))]
+impl<M, S> AsRawFd for Backend<M, S>
+    where M: AsRawFd
+{
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Backend::Mio(ref io) => io.as_raw_fd(),
+            Backend::Sim(_) => -1,
+        }
+    }
+}
+
+/// Error returned by operations simulated sockets don't support
+#[cfg(feature = "sim")]
+pub fn sim_unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "not supported by simulated sockets")
+}
+
 pub trait RcEventSourceTrait {
     /// Reregister oneshot handler for the next event
     fn register(&mut self, event_loop: &mut EventLoop<Handler>, co_id: coroutine::Id);
@@ -304,9 +487,13 @@ pub trait RcEventSourceTrait {
 
     fn hup(&mut self, _event_loop: &mut EventLoop<Handler>, token: Token);
 
+    fn id(&self) -> EventSourceId;
+
     fn blocked_on(&self) -> RW;
 
     fn should_resume(&self) -> bool;
+
+    fn on_resume(&self);
 }
 
 /// Common control data for all event sources.
@@ -375,6 +562,10 @@ impl<T> RcEventSource<T> {
 
 impl<T> RcEventSourceTrait for RcEventSource<T> where T: EventSourceTrait
 {
+    fn id(&self) -> EventSourceId {
+        self.0.borrow().common.id.unwrap()
+    }
+
     fn blocked_on(&self) -> RW {
         self.0.borrow().common.blocked_on
     }
@@ -389,6 +580,10 @@ impl<T> RcEventSourceTrait for RcEventSource<T> where T: EventSourceTrait
         self.0.borrow().io.should_resume()
     }
 
+    fn on_resume(&self) {
+        self.0.borrow().io.on_resume()
+    }
+
     /// Reregister oneshot handler for the next event
     fn register(&mut self, event_loop: &mut EventLoop<Handler>, co_id: coroutine::Id) {
         let mut interest = mio_orig::EventSet::none();
@@ -465,4 +660,8 @@ impl<T> EventSourceTrait for RcEventSource<T> where T: EventSourceTrait
     fn should_resume(&self) -> bool {
         self.0.borrow().io.should_resume()
     }
+
+    fn on_resume(&self) {
+        self.0.borrow().io.on_resume()
+    }
 }
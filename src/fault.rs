@@ -0,0 +1,118 @@
+use super::rng::XorShift;
+
+use spin::Mutex;
+
+use std::io;
+
+/// IO fault injection settings
+///
+/// Makes `try_read()`/`try_write()` of `MioAdapter` based event sources
+/// (TCP, unix sockets, pipes and in-memory streams) misbehave the way real
+/// IO is allowed to: return less data than asked for, report `WouldBlock`
+/// although the source is ready, or fail with `ConnectionReset` or
+/// `Interrupted`. Each kind of fault happens with given probability
+/// (`0.0 - 1.0`) on every call. Decisions are made by a PRNG seeded with
+/// `seed`.
+///
+/// Enable for all event sources created in a mioco instance with
+/// `Config::set_faults()`, or for a single one with
+/// `MioAdapter::set_faults()`.
+#[derive(Copy, Clone, Debug)]
+pub struct Faults {
+    seed: u64,
+    short_read: f64,
+    partial_write: f64,
+    would_block: f64,
+    connection_reset: f64,
+    interrupted: f64,
+}
+
+impl Faults {
+    /// Create settings with no faults enabled.
+    pub fn new(seed: u64) -> Self {
+        Faults {
+            seed: seed,
+            short_read: 0.0,
+            partial_write: 0.0,
+            would_block: 0.0,
+            connection_reset: 0.0,
+            interrupted: 0.0,
+        }
+    }
+
+    /// Probability of a read returning only a part of the available data.
+    pub fn set_short_read(&mut self, probability: f64) -> &mut Self {
+        self.short_read = probability;
+        self
+    }
+
+    /// Probability of a write accepting only a part of the buffer.
+    pub fn set_partial_write(&mut self, probability: f64) -> &mut Self {
+        self.partial_write = probability;
+        self
+    }
+
+    /// Probability of a spurious `WouldBlock`.
+    pub fn set_would_block(&mut self, probability: f64) -> &mut Self {
+        self.would_block = probability;
+        self
+    }
+
+    /// Probability of failing with `ConnectionReset`.
+    pub fn set_connection_reset(&mut self, probability: f64) -> &mut Self {
+        self.connection_reset = probability;
+        self
+    }
+
+    /// Probability of failing with `Interrupted`.
+    pub fn set_interrupted(&mut self, probability: f64) -> &mut Self {
+        self.interrupted = probability;
+        self
+    }
+}
+
+/// Fault to inject into a single IO operation
+pub enum Fault {
+    /// Operate on `n` bytes only
+    Partial(usize),
+    WouldBlock,
+    Error(io::ErrorKind),
+}
+
+/// `Faults` with PRNG state, shared by event sources using them
+pub struct FaultInjector {
+    faults: Faults,
+    rng: Mutex<XorShift>,
+}
+
+impl FaultInjector {
+    pub fn new(faults: Faults) -> Self {
+        FaultInjector {
+            faults: faults,
+            rng: Mutex::new(XorShift::new(faults.seed)),
+        }
+    }
+
+    pub fn read_fault(&self, len: usize) -> Option<Fault> {
+        self.pick(len, self.faults.short_read)
+    }
+
+    pub fn write_fault(&self, len: usize) -> Option<Fault> {
+        self.pick(len, self.faults.partial_write)
+    }
+
+    fn pick(&self, len: usize, partial: f64) -> Option<Fault> {
+        let mut rng = self.rng.lock();
+        if rng.chance(self.faults.connection_reset) {
+            Some(Fault::Error(io::ErrorKind::ConnectionReset))
+        } else if rng.chance(self.faults.interrupted) {
+            Some(Fault::Error(io::ErrorKind::Interrupted))
+        } else if rng.chance(self.faults.would_block) {
+            Some(Fault::WouldBlock)
+        } else if len > 1 && rng.chance(partial) {
+            Some(Fault::Partial(1 + rng.below(len - 1)))
+        } else {
+            None
+        }
+    }
+}
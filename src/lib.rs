@@ -22,9 +22,12 @@
 //! * user-provided scheduling; (see `Config::set_scheduler()`);
 //! * timers (see `MiocoHandle::timer()`);
 //! * mailboxes (see `mailbox()`);
+//! * in-memory streams (see `mem::duplex()`);
 //! * coroutine exit notification (see `CoroutineHandle::exit_notificator()`).
 //! * synchronous operations support (see `MiocoHandle::sync()`).
-//! * synchronization primitives (see `RwLock`).
+//! * synchronization primitives (see `sync` module).
+//! * coroutine lifecycle tracing (see `Config::set_tracer()`).
+//! * simulated network for tests (see `sim::SimNetwork`, `sim` feature).
 //! ```
 //!
 //! # <a name="example"/></a> Example:
@@ -44,6 +47,8 @@
 #![feature(cell_extras)]
 #![feature(as_unsafe_cell)]
 #![feature(reflect_marker)]
+#![feature(thread_local)]
+#![feature(mpsc_select)]
 #![warn(missing_docs)]
 #![allow(private_in_public)]
 
@@ -63,6 +68,8 @@ extern crate log;
 extern crate time;
 extern crate num_cpus;
 extern crate slab;
+#[macro_use]
+extern crate lazy_static;
 
 /// Re-export of some `mio` symbols, that are part of the mioco API.
 pub mod mio {
@@ -74,7 +81,8 @@ use std::cell::{RefCell};
 use std::rc::Rc;
 use std::io;
 use std::marker::Reflect;
-use std::mem::{self};
+use std::mem as std_mem;
+use std::fmt;
 
 use mio_orig::{Token, EventLoop, EventLoopConfig};
 
@@ -84,7 +92,12 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 
 use std::ptr;
 
-use timer::Timer;
+use timer::{Timer, Clock, VirtualClock};
+use trace::Tracer;
+use stats::Stats;
+use fault::{Faults, FaultInjector};
+use sim::SimHost;
+use time::Duration;
 
 /// Useful synchronization primitives
 pub mod sync;
@@ -99,6 +112,17 @@ pub mod tcp;
 pub mod udp;
 /// Mailboxes
 pub mod mail;
+/// In-memory streams
+pub mod mem;
+/// Coroutine lifecycle tracing
+pub mod trace;
+/// Runtime statistics
+pub mod stats;
+/// Testing aids
+pub mod test;
+pub mod sim;
+/// IO fault injection
+pub mod fault;
 
 pub use evented::{Evented, MioAdapter};
 mod evented;
@@ -111,6 +135,14 @@ pub use thread::Handler;
 use thread::Message;
 mod thread;
 
+mod overflow;
+
+mod rng;
+
+mod wake;
+
+mod mpsc_queue;
+
 /// Read/Write/Both/None
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 // TODO: Make private again
@@ -280,12 +312,37 @@ impl CoroutineHandle {
         } = *co;
 
         if let &coroutine::State::Finished(ref exit) = state {
-            outer.send(exit.clone())
+            let _ = outer.send(exit.clone());
         } else {
             exit_notificators.push(outer);
         }
         inner
     }
+
+    /// Stack high-water mark of a finished coroutine, in bytes
+    ///
+    /// Returns `None` if the coroutine is still running or if stack usage
+    /// tracking was not enabled (see `Config::set_stack_usage_tracking()`).
+    pub fn stack_usage(&self) -> Option<usize> {
+        self.coroutine.borrow().stack_usage
+    }
+
+    /// Wall-clock time the coroutine spent running so far
+    ///
+    /// Measured between being switched in and out, so it includes time
+    /// the thread was preempted by the OS: this is not CPU time. Zero
+    /// unless enabled with `Config::set_timing_stats()`.
+    pub fn run_time(&self) -> Duration {
+        self.coroutine.borrow().run_time
+    }
+
+    /// Time the coroutine spent so far waiting to be resumed after
+    /// becoming ready
+    ///
+    /// Zero unless enabled with `Config::set_timing_stats()`.
+    pub fn sched_latency(&self) -> Duration {
+        self.coroutine.borrow().sched_latency
+    }
 }
 
 /// Coroutine Scheduler
@@ -450,6 +507,9 @@ impl CoroutineControl {
         let co_rc = self.rc.clone();
         let is_ready = co_rc.borrow().state().is_ready();
         if is_ready {
+            if let Some((tracer, info)) = co_rc.borrow().tracer() {
+                tracer.resumed(info);
+            }
             coroutine::jump_in(&co_rc);
             self.after_resume(event_loop);
         } else {
@@ -496,6 +556,10 @@ impl CoroutineControl {
                    thread_id);
             let mut co = self.rc.borrow_mut();
 
+            if let Some((tracer, info)) = co.tracer() {
+                tracer.migrated(info, thread_id);
+            }
+
             let handler_shared = co.detach_from(event_loop);
             let mut handler_shared = handler_shared.borrow_mut();
             handler_shared.coroutines.remove(co.id).unwrap();
@@ -549,6 +613,7 @@ impl CoroutineControl {
 pub struct Mioco {
     join_handles: Vec<std::thread::JoinHandle<()>>,
     config: Config,
+    stats: Arc<Stats>,
 }
 
 impl Mioco {
@@ -562,9 +627,18 @@ impl Mioco {
         Mioco {
             join_handles: Vec::new(),
             config: config,
+            stats: Arc::new(Stats::new()),
         }
     }
 
+    /// Runtime statistics of this instance
+    ///
+    /// Can be inspected while `start()` is running (eg. from a different
+    /// thread) or after it returned.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
     /// Start mioco handling
     ///
     /// Takes a starting handler function that will be executed in `mioco` environment.
@@ -578,7 +652,8 @@ impl Mioco {
     {
         info!("Starting mioco instance with {} handler threads",
               self.config.thread_num);
-        let thread_shared = Arc::new(thread::HandlerThreadShared::new(self.config.thread_num));
+        let thread_shared = Arc::new(thread::HandlerThreadShared::new(self.config.thread_num,
+                                                                          self.stats.clone()));
 
         let mut event_loops = VecDeque::new();
         let mut senders = Vec::new();
@@ -595,8 +670,7 @@ impl Mioco {
         for i in 1..self.config.thread_num {
 
             let scheduler = self.config.scheduler.clone();
-            let stack_size = self.config.stack_size;
-            let catch_panics = self.config.catch_panics;
+            let thread_config = self.config.thread_config();
             let event_loop = event_loops.pop_front().unwrap();
             let senders = senders.clone();
             let thread_shared = thread_shared.clone();
@@ -610,9 +684,8 @@ impl Mioco {
                                                        i,
                                                        senders,
                                                        thread_shared,
-                                                       stack_size,
                                                        None,
-                                                       catch_panics);
+                                                       thread_config);
                            });
 
             match join {
@@ -622,20 +695,23 @@ impl Mioco {
         }
 
         let mut user_data = None;
-        mem::swap(&mut user_data, &mut self.config.user_data);
+        std_mem::swap(&mut user_data, &mut self.config.user_data);
         Mioco::thread_loop(Some(f),
                            sched,
                            first_event_loop,
                            0,
                            senders,
                            thread_shared,
-                           self.config.stack_size,
                            user_data,
-                           self.config.catch_panics);
+                           self.config.thread_config());
 
         for join in self.join_handles.drain(..) {
             let _ = join.join(); // TODO: Do something with it
         }
+
+        if let Some(ref tracer) = self.config.tracer {
+            tracer.stopped();
+        }
     }
 
     fn thread_loop<F>(f: Option<F>,
@@ -644,13 +720,23 @@ impl Mioco {
                       thread_id: usize,
                       senders: Vec<thread::MioSender>,
                       thread_shared: thread::ArcHandlerThreadShared,
-                      stack_size: usize,
                       userdata: Option<Arc<Box<Any + Send + Sync>>>,
-                      catch_panics: bool)
+                      config: thread::ThreadConfig)
         where F: FnOnce() -> io::Result<()> + Send + 'static,
               F: Send
     {
-        let handler_shared = thread::HandlerShared::new(senders, thread_shared, stack_size, thread_id);
+        let _alt_stack = if config.overflow_reporting {
+            overflow::init();
+            Some(overflow::AltStack::new())
+        } else {
+            None
+        };
+
+        let catch_panics = config.catch_panics;
+        let handler_shared = thread::HandlerShared::new(senders,
+                                                        thread_shared,
+                                                        thread_id,
+                                                        config);
         let shared = Rc::new(RefCell::new(handler_shared));
         if let Some(f) = f {
             let coroutine_rc = Coroutine::spawn(shared.clone(), userdata, f, catch_panics);
@@ -678,8 +764,15 @@ pub struct Config {
     scheduler: Arc<Box<Scheduler>>,
     event_loop_config: EventLoopConfig,
     stack_size: usize,
+    stack_usage_tracking: bool,
+    timing_stats: bool,
+    stack_overflow_reporting: bool,
     user_data: Option<Arc<Box<Any + Send + Sync>>>,
     catch_panics: bool,
+    tracer: Option<Arc<Box<Tracer>>>,
+    clock: Clock,
+    faults: Option<Arc<FaultInjector>>,
+    sim_host: Option<SimHost>,
 }
 
 impl Config {
@@ -694,12 +787,34 @@ impl Config {
             scheduler: Arc::new(Box::new(FifoScheduler::new())),
             event_loop_config: Default::default(),
             stack_size: 2 * 1024 * 1024,
+            stack_usage_tracking: false,
+            timing_stats: false,
+            stack_overflow_reporting: false,
             user_data: None,
             catch_panics: true,
+            tracer: None,
+            clock: Clock::Real,
+            faults: None,
+            sim_host: None,
         };
         config
     }
 
+    /// Per-thread part of the configuration
+    fn thread_config(&self) -> thread::ThreadConfig {
+        thread::ThreadConfig {
+            stack_size: self.stack_size,
+            stack_usage_tracking: self.stack_usage_tracking,
+            timing_stats: self.timing_stats,
+            overflow_reporting: self.stack_overflow_reporting,
+            catch_panics: self.catch_panics,
+            tracer: self.tracer.clone(),
+            clock: self.clock.clone(),
+            faults: self.faults.clone(),
+            sim_host: self.sim_host.clone(),
+        }
+    }
+
     /// Set numer of threads to run mioco with
     ///
     /// Default is equal to a numer of CPUs in the system.
@@ -732,11 +847,63 @@ impl Config {
     /// lead to SEGFAULTs. See [context-rs stack.rs](https://github.com/zonyitoo/context-rs/blob/master/src/stack.rs)
     /// for implementation details. The sane minimum seems to be 128KiB,
     /// which is two 64KB pages.
+    ///
+    /// See also `set_stack_overflow_reporting()` and
+    /// `set_stack_usage_tracking()`.
     pub unsafe fn set_stack_size(&mut self, stack_size: usize) -> &mut Self {
         self.stack_size = stack_size;
         self
     }
 
+    /// Measure how much stack coroutines actually use.
+    ///
+    /// When enabled, each coroutine stack is filled with a known pattern
+    /// when allocated, and on coroutine exit the deepest overwritten
+    /// address is found. Results are available through
+    /// `CoroutineHandle::stack_usage()` and as histograms by coroutine name
+    /// in `Stats::stack_usage()`, which helps picking a safe value for
+    /// `set_stack_size()`.
+    ///
+    /// This makes spawning more expensive, and commits the whole stack
+    /// memory of every coroutine, so it is meant for diagnostics only.
+    ///
+    /// Default is disabled.
+    pub fn set_stack_usage_tracking(&mut self, enabled: bool) -> &mut Self {
+        self.stack_usage_tracking = enabled;
+        self
+    }
+
+    /// Measure coroutine run time and scheduling latency.
+    ///
+    /// When enabled, every context switch reads the clock, and results
+    /// are available through `CoroutineHandle::run_time()`,
+    /// `CoroutineHandle::sched_latency()` and histograms in `Stats`.
+    /// Run time is wall-clock time between resume and yield, not CPU time.
+    ///
+    /// Default is disabled.
+    pub fn set_timing_stats(&mut self, enabled: bool) -> &mut Self {
+        self.timing_stats = enabled;
+        self
+    }
+
+    /// Report coroutine stack overflows.
+    ///
+    /// When enabled, a coroutine overflowing its stack is reported (with
+    /// its id and name) on stderr before the process aborts, instead of
+    /// dying with a bare SIGSEGV.
+    ///
+    /// This installs process-wide SIGSEGV and SIGBUS handlers, and an
+    /// alternate signal stack on mioco threads that don't have one. Faults
+    /// outside coroutine protection pages are passed on to the handlers
+    /// that were installed before. Only supported on Linux on x86, x86_64,
+    /// ARM and AArch64; a noop elsewhere.
+    ///
+    /// Default is disabled.
+    pub fn set_stack_overflow_reporting(&mut self, enabled: bool) -> &mut Self {
+        self.stack_overflow_reporting = enabled;
+        self
+    }
+
     /// Set the user data of the first spawned coroutine
     ///
     /// Default is no Userdata
@@ -755,6 +922,51 @@ impl Config {
         self.catch_panics = catch_panics;
         self
     }
+
+    /// Drive timers with a virtual clock instead of real time.
+    ///
+    /// See `timer::VirtualClock`.
+    ///
+    /// Default is real time.
+    pub fn set_virtual_clock(&mut self, clock: VirtualClock) -> &mut Self {
+        self.clock = Clock::Virtual(clock);
+        self
+    }
+
+    /// Inject IO faults into event sources created in this instance.
+    ///
+    /// See `fault::Faults`.
+    ///
+    /// Default is no fault injection.
+    pub fn set_faults(&mut self, faults: Faults) -> &mut Self {
+        self.faults = Some(Arc::new(FaultInjector::new(faults)));
+        self
+    }
+
+    /// Run coroutines of this instance on a simulated network host.
+    ///
+    /// TCP, UDP and unix sockets they create are simulated sockets of
+    /// `host` instead of real ones. Individual coroutines can be moved to
+    /// other hosts with `sim::set_current_host()`.
+    ///
+    /// See `sim::SimNetwork`.
+    ///
+    /// Default is the real network. Requires the `sim` feature.
+    #[cfg(feature = "sim")]
+    pub fn set_sim_host(&mut self, host: SimHost) -> &mut Self {
+        self.sim_host = Some(host);
+        self
+    }
+
+    /// Set a tracer receiving coroutine lifecycle events.
+    ///
+    /// See `trace::Tracer`.
+    ///
+    /// Default is no tracer.
+    pub fn set_tracer(&mut self, tracer: Box<Tracer + 'static>) -> &mut Self {
+        self.tracer = Some(Arc::new(tracer));
+        self
+    }
 }
 
 // TODO: Technically this leaks unsafe, but only within
@@ -875,12 +1087,12 @@ pub fn sync<'b, F, R>(f: F) -> R
         thread_scoped::scoped(move || {
             let FakeSend(f) = f;
             let res = f();
-            mail_send.send(());
+            let _ = mail_send.send(());
             FakeSend(res)
         })
     };
 
-    mail_recv.read();
+    let _ = mail_recv.read();
 
     let FakeSend(res) = join.join();
     res
@@ -914,6 +1126,91 @@ pub fn set_children_userdata<T: Reflect + Send + Sync + 'static>(data: Option<T>
     }
 }
 
+/// Sets the name of the current coroutine
+///
+/// The name is reported by `current()`, which makes it show up in log
+/// records formatted with `log_format()`.
+pub fn set_name<S: Into<String>>(name: S) {
+    let coroutine = tl_coroutine_current();
+    let _prev = std_mem::replace(&mut coroutine.name, Some(Arc::new(name.into())));
+    // Point the overflow handler at the new name before the old one is freed
+    if let Some(current) = coroutine.overflow_current() {
+        overflow::swap_current(current);
+    }
+}
+
+/// Identification of a coroutine
+///
+/// See `current()`.
+#[derive(Clone, Debug)]
+pub struct CoroutineInfo {
+    /// Coroutine id; unique within the process
+    pub id: usize,
+    /// Coroutine name, if set with `set_name()`
+    pub name: Option<Arc<String>>,
+    /// Id of the mioco thread the coroutine is executing on
+    pub thread_id: usize,
+}
+
+impl fmt::Display for CoroutineInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "[t{} c{} {}]", self.thread_id, self.id, name),
+            None => write!(f, "[t{} c{}]", self.thread_id, self.id),
+        }
+    }
+}
+
+/// Get identification of the current coroutine
+///
+/// Returns `None` when not executing inside a mioco coroutine.
+pub fn current() -> Option<CoroutineInfo> {
+    if !in_coroutine() {
+        return None;
+    }
+
+    let coroutine = tl_coroutine_current();
+    Some(CoroutineInfo {
+        id: coroutine.uid,
+        name: coroutine.name.clone(),
+        thread_id: coroutine.handler_shared().thread_id(),
+    })
+}
+
+/// Format a `log` record, prefixed with the current coroutine (if any)
+///
+/// Meant to be used as a format function of a logger, eg.
+/// `env_logger::LogBuilder::format()`, to make interleaved records from
+/// many coroutines possible to tell apart:
+///
+/// ```norust
+/// INFO:server: [t1 c12 parser] malformed request
+/// ```
+pub fn log_format(record: &log::LogRecord) -> String {
+    match current() {
+        Some(co) => {
+            format!("{}:{}: {} {}",
+                    record.level(),
+                    record.location().module_path(),
+                    co,
+                    record.args())
+        }
+        None => {
+            format!("{}:{}: {}",
+                    record.level(),
+                    record.location().module_path(),
+                    record.args())
+        }
+    }
+}
+
+/// Runtime statistics of the Mioco instance that coroutine is running in.
+pub fn stats() -> Arc<Stats> {
+    let coroutine = tl_coroutine_current();
+
+    coroutine.handler_shared().stats().clone()
+}
+
 /// Get number of threads of the Mioco instance that coroutine is
 /// running in.
 ///
@@ -1,26 +1,305 @@
-use super::RW;
+use super::{RW, in_coroutine, select_wait, tl_coroutine_current};
+use super::timer::{self, Clock, Timer};
 use super::thread::{Handler, Message};
 use super::evented::{EventSourceTrait, RcEventSource, Evented, EventedImpl};
-use super::mio_orig::{EventLoop, Token, EventSet};
+use super::mio_orig::{EventLoop, Token, EventSet, NotifyError};
+use super::wake::Waker;
+use super::mpsc_queue::{Queue, PopResult};
+use std::cell::Cell;
+use std::mem;
 use std::sync::Arc;
+use std::sync::{mpsc, Condvar};
+use std::sync::Mutex as StdMutex;
+use std::boxed::FnBox;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::Duration as StdDuration;
+use time::{SteadyTime, Duration};
 use spin::Mutex;
 use super::thread::MioSender;
-use std::collections::VecDeque;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BTreeMap, BinaryHeap, HashSet, VecDeque};
 use super::sender_retry;
 
 type MailboxQueue<T> = Option<T>;
-type ArcMailboxShared<T> = Arc<Mutex<MailboxShared<T>>>;
+type ArcMailboxShared<T> = Arc<MailboxShared<T>>;
 
+/// State shared between ends of a mailbox
+///
+/// Messages go through a lock-free queue. Sending does not take any lock,
+/// unless the receiving end is waiting for a message: the receiver sets
+/// `receiver_waiting` before it blocks (and then checks the queue again),
+/// and a sender that finds it set after pushing sends a notification.
+///
+/// A push is finished only when the message can be taken out of the queue,
+/// so a receiver finding a push in progress just waits for the
+/// notification, like for any other message.
 struct MailboxShared<T> {
+    inn: Queue<T>,
+    /// Number of messages in `inn`, including ones being pushed
+    len: AtomicUsize,
+    /// Maximum length of `inn`; `None` for unbounded mailboxes
+    capacity: Option<usize>,
+    /// Receiving end is waiting for a notification
+    receiver_waiting: AtomicBool,
+    /// Number of sending ends alive
+    senders: AtomicUsize,
+    /// Receiving end is alive
+    receiver_alive: AtomicBool,
+    waiters: Mutex<MailboxWaiters>,
+}
+
+/// Blocked ends of a mailbox
+struct MailboxWaiters {
     token: Option<Token>,
     sender: Option<MioSender>,
-    inn: VecDeque<T>,
     interest: EventSet,
+    /// Senders waiting for free space, in order of arrival
+    blocked_senders: VecDeque<BlockedSender>,
+    /// Senders woken up, that did not take their free slot yet, by id
+    woken_senders: HashSet<usize>,
+    /// Thread waiting for a message (see `MailboxInnerEnd::read()`)
+    parked_receiver: Option<thread::Thread>,
+}
+
+impl<T> MailboxShared<T> {
+    fn new(capacity: Option<usize>) -> Self {
+        MailboxShared {
+            inn: Queue::new(),
+            len: AtomicUsize::new(0),
+            capacity: capacity,
+            receiver_waiting: AtomicBool::new(false),
+            senders: AtomicUsize::new(0),
+            receiver_alive: AtomicBool::new(true),
+            waiters: Mutex::new(MailboxWaiters {
+                token: None,
+                sender: None,
+                interest: EventSet::none(),
+                blocked_senders: VecDeque::new(),
+                woken_senders: HashSet::new(),
+                parked_receiver: None,
+            }),
+        }
+    }
+
+    /// Is the receiving end ready: has a message or will never get one
+    ///
+    /// Only the receiving end can call it. A message still being pushed
+    /// does not count: its sender notifies once it's done.
+    fn is_readable(&self) -> bool {
+        unsafe { self.inn.has_data() } || self.senders.load(Ordering::SeqCst) == 0
+    }
+
+    /// Is a sending end ready: has room for a message or there is no
+    /// receiver
+    fn is_writable(&self) -> bool {
+        !self.is_full() || !self.receiver_alive.load(Ordering::SeqCst)
+    }
+
+    fn is_full(&self) -> bool {
+        self.capacity.map_or(false,
+                             |capacity| self.len.load(Ordering::SeqCst) >= capacity)
+    }
+
+    /// Mark the receiving end as waiting for a notification.
+    ///
+    /// Must be called with `waiters` locked, after registering. Returns
+    /// `true` if the receiving end is ready already, and should not wait.
+    fn receiver_wait(&self) -> bool {
+        self.receiver_waiting.store(true, Ordering::SeqCst);
+        // Pairs with the fence in `notify_receiver()`: either the sender
+        // sees the flag, or we see its message
+        atomic::fence(Ordering::SeqCst);
+        if self.is_readable() {
+            self.receiver_waiting.store(false, Ordering::SeqCst);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn notify_receiver(&self) {
+        atomic::fence(Ordering::SeqCst);
+        if !self.receiver_waiting.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let mut waiters = self.waiters.lock();
+        if let Some(thread) = waiters.parked_receiver.take() {
+            trace!("MailboxOuterEnd: unparking receiver");
+            thread.unpark();
+        }
+
+        if waiters.interest.is_readable() {
+            let token = waiters.token.unwrap();
+            trace!("MailboxOuterEnd: notifying {:?}", token);
+            let sender = waiters.sender.as_ref().unwrap();
+            sender_retry(&sender, Message::MailboxMsg(token))
+        }
+    }
+
+    /// Number of messages that can be sent without blocking
+    fn free_slots(&self) -> usize {
+        if !self.receiver_alive.load(Ordering::SeqCst) {
+            return usize::max_value();
+        }
+        self.capacity.map_or(usize::max_value(), |capacity| {
+            capacity.saturating_sub(self.len.load(Ordering::SeqCst))
+        })
+    }
+
+    /// Take blocked senders to wake up: one per free slot not promised to
+    /// already woken senders, oldest first.
+    ///
+    /// Wake them after unlocking `waiters`.
+    fn dispatch_senders(&self, waiters: &mut MailboxWaiters) -> Vec<BlockedSender> {
+        let free = self.free_slots();
+        let mut to_wake = Vec::new();
+        while waiters.woken_senders.len() < free {
+            match waiters.blocked_senders.pop_front() {
+                Some(sender) => {
+                    waiters.woken_senders.insert(sender.id());
+                    to_wake.push(sender);
+                }
+                None => break,
+            }
+        }
+        to_wake
+    }
+
+    fn notify_senders(&self) {
+        let to_wake = {
+            let mut waiters = self.waiters.lock();
+            self.dispatch_senders(&mut waiters)
+        };
+        for sender in to_wake {
+            sender.wake();
+        }
+    }
+
+    fn sender_added(&self) {
+        self.senders.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn sender_dropped(&self) {
+        if self.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            trace!("MailboxOuterEnd: last sender dropped");
+            self.notify_receiver();
+        }
+    }
+
+    fn push(&self, t: T) {
+        let len = self.len.fetch_add(1, Ordering::SeqCst);
+        self.inn.push(t);
+        trace!("MailboxOuterEnd: putting message in a queue; new len: {}",
+               len + 1);
+
+        self.notify_receiver();
+    }
+
+    /// Like `push()`, but fails if the mailbox is full
+    fn try_push(&self, t: T) -> Result<(), T> {
+        let capacity = self.capacity.unwrap_or(usize::max_value());
+        let mut len = self.len.load(Ordering::SeqCst);
+        loop {
+            if len >= capacity {
+                return Err(t);
+            }
+            match self.len.compare_exchange(len, len + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => break,
+                Err(prev) => len = prev,
+            }
+        }
+        self.inn.push(t);
+
+        self.notify_receiver();
+        Ok(())
+    }
+
+    /// Like `push()`, for many messages, with a single notification
+    fn push_batch<I: Iterator<Item = T>>(&self, iter: I) {
+        for t in iter {
+            self.len.fetch_add(1, Ordering::SeqCst);
+            self.inn.push(t);
+        }
+
+        self.notify_receiver();
+    }
+
+    /// Take a message out of `inn`; only the receiving end can call it
+    ///
+    /// Messages still being pushed are not there yet: the receiving end
+    /// waits for their notification (see `is_readable()`).
+    fn take(&self) -> Option<T> {
+        match unsafe { self.inn.pop() } {
+            PopResult::Data(t) => {
+                self.len.fetch_sub(1, Ordering::SeqCst);
+                Some(t)
+            }
+            PopResult::Empty | PopResult::Inconsistent => None,
+        }
+    }
+
+    /// Like `pop()`, for at most `max` messages
+    fn pop_batch(&self, max: usize) -> Vec<T> {
+        let mut batch = Vec::new();
+        while batch.len() < max {
+            match self.take() {
+                Some(t) => batch.push(t),
+                None => break,
+            }
+        }
+
+        if !batch.is_empty() && self.capacity.is_some() {
+            self.notify_senders();
+        }
+
+        batch
+    }
+
+    fn pop(&self) -> Option<T> {
+        let t = self.take();
+
+        if t.is_some() && self.capacity.is_some() {
+            self.notify_senders();
+        }
+
+        t
+    }
+}
+
+/// Error returned by `MailboxInnerEnd::read()`: the mailbox is empty and
+/// all sending ends were dropped
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+/// Error returned by `MailboxInnerEnd::try_read()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No messages in the mailbox
+    Empty,
+    /// No messages in the mailbox and all sending ends were dropped
+    Disconnected,
+}
+
+/// Error returned by `MailboxInnerEnd::read_timeout()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    /// No message arrived in time
+    Timeout,
+    /// No messages in the mailbox and all sending ends were dropped
+    Disconnected,
 }
 
+/// Error returned by `send()`: the receiving end was dropped; the value
+/// is returned
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
 /// Mailbox receiving end
 ///
-/// Use this only inside mioco coroutines, as an asynchronous event source.
+/// Inside mioco coroutines this is an asynchronous event source. It can
+/// also be read from any other thread, which is then parked while waiting.
 ///
 /// Create with `mailbox()`
 pub struct MailboxInnerEnd<T>(RcEventSource<MailboxInnerCore<T>>);
@@ -39,44 +318,54 @@ impl<T> EventedImpl for MailboxInnerEnd<T> where T: 'static
 impl<T> EventSourceTrait for MailboxInnerCore<T> {
     fn register(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
         trace!("MailboxInnerEnd({}): register", token.as_usize());
-        let mut lock = self.0.lock();
+        let mut waiters = self.0.waiters.lock();
 
-        lock.token = Some(token);
-        lock.sender = Some(event_loop.channel());
-        lock.interest = interest;
+        waiters.token = Some(token);
+        waiters.sender = Some(event_loop.channel());
+        waiters.interest = interest;
 
-        if interest.is_readable() && !lock.inn.is_empty() {
-            trace!("MailboxInnerEnd({}): not empty; self notify",
+        if interest.is_readable() && self.0.receiver_wait() {
+            trace!("MailboxInnerEnd({}): ready; self notify",
                    token.as_usize());
-            lock.interest = EventSet::none();
-            sender_retry(lock.sender.as_ref().unwrap(), Message::MailboxMsg(token));
+            waiters.interest = EventSet::none();
+            sender_retry(waiters.sender.as_ref().unwrap(), Message::MailboxMsg(token));
         }
     }
 
     fn reregister(&self, _event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
         trace!("MailboxInnerEnd({}): reregister", token.as_usize());
-        let mut lock = self.0.lock();
+        let mut waiters = self.0.waiters.lock();
 
-        lock.interest = interest;
+        waiters.interest = interest;
 
-        if interest.is_readable() && !lock.inn.is_empty() {
-            lock.interest = EventSet::none();
-            sender_retry(lock.sender.as_ref().unwrap(), Message::MailboxMsg(token));
+        if interest.is_readable() && self.0.receiver_wait() {
+            waiters.interest = EventSet::none();
+            sender_retry(waiters.sender.as_ref().unwrap(), Message::MailboxMsg(token));
         }
     }
 
     fn deregister(&self, _event_loop: &mut EventLoop<Handler>, token: Token) {
         trace!("MailboxInnerEnd({}): dereregister", token.as_usize());
-        let mut lock = self.0.lock();
-        lock.token = None;
-        lock.sender = None;
-        lock.interest = EventSet::none();
+        let mut waiters = self.0.waiters.lock();
+        self.0.receiver_waiting.store(false, Ordering::SeqCst);
+        waiters.token = None;
+        waiters.sender = None;
+        waiters.interest = EventSet::none();
     }
 
     fn should_resume(&self) -> bool {
-        let lock = self.0.lock();
-        trace!("MailboxInnerEnd: should_resume? {}", !lock.inn.is_empty());
-        !lock.inn.is_empty()
+        let readable = self.0.is_readable();
+        trace!("MailboxInnerEnd: should_resume? {}", readable);
+        readable
+    }
+}
+
+impl<T> Drop for MailboxInnerCore<T> {
+    fn drop(&mut self) {
+        self.0.receiver_alive.store(false, Ordering::SeqCst);
+        // Messages still being pushed are dropped together with the queue
+        while self.0.take().is_some() {}
+        self.0.notify_senders();
     }
 }
 
@@ -93,16 +382,23 @@ pub struct MailboxOuterEnd<T> {
 
 impl<T> Clone for MailboxOuterEnd<T> {
     fn clone(&self) -> Self {
-        MailboxOuterEnd { shared: self.shared.clone() }
+        MailboxOuterEnd::new(self.shared.clone())
     }
 }
 
 impl<T> MailboxOuterEnd<T> {
     fn new(shared: ArcMailboxShared<T>) -> Self {
+        shared.sender_added();
         MailboxOuterEnd { shared: shared }
     }
 }
 
+impl<T> Drop for MailboxOuterEnd<T> {
+    fn drop(&mut self) {
+        self.shared.sender_dropped();
+    }
+}
+
 impl<T> MailboxInnerEnd<T> {
     fn new(shared: ArcMailboxShared<T>) -> Self {
         MailboxInnerEnd(RcEventSource::new(MailboxInnerCore(shared)))
@@ -114,27 +410,214 @@ impl<T> MailboxOuterEnd<T> {
     ///
     /// Mailbox behaves like a queue.
     ///
+    /// This is non-blocking operation. Fails if the receiving end was
+    /// dropped. A message sent while the receiving end is being dropped
+    /// might be dropped without an error.
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        if !self.shared.receiver_alive.load(Ordering::SeqCst) {
+            return Err(SendError(t));
+        }
+        self.shared.push(t);
+        Ok(())
+    }
+
+    /// Deliver many messages to the other end of the mailbox at once.
+    ///
+    /// Cheaper than calling `send()` for every message: the receiving end
+    /// is notified only once. Fails, returning all the messages, if the
+    /// receiving end was dropped.
+    pub fn send_batch<I>(&self, iter: I) -> Result<(), SendError<Vec<T>>>
+        where I: IntoIterator<Item = T>
+    {
+        if !self.shared.receiver_alive.load(Ordering::SeqCst) {
+            return Err(SendError(iter.into_iter().collect()));
+        }
+        self.shared.push_batch(iter.into_iter());
+        Ok(())
+    }
+}
+
+impl<T> MailboxOuterEnd<T> where T: Send + 'static
+{
+    /// Deliver `T` to the other end of the mailbox after `delay_ms`.
+    ///
+    /// See `send_at()`.
+    pub fn send_after(&self, delay_ms: i64, t: T) -> DelayedSend<T> {
+        let deadline = timer::now() + Duration::milliseconds(delay_ms);
+        self.send_at(deadline, t)
+    }
+
+    /// Deliver `T` to the other end of the mailbox at `deadline`.
+    ///
+    /// `deadline` is in the time of `timer::now()`. Inside coroutines the
+    /// delivery is scheduled on the event loop timer of the current
+    /// thread, following the clock of the mioco instance. Deliveries still
+    /// pending when the instance finishes are dropped. Outside of
+    /// coroutines deliveries are made by one shared timer thread, in real
+    /// time.
+    ///
+    /// Delivery is silently dropped if the receiving end is gone by then.
     /// This is non-blocking operation.
-    pub fn send(&self, t: T) {
-        let mut lock = self.shared.lock();
-        let MailboxShared {
-            ref mut sender,
-            ref mut token,
-            ref mut inn,
-            ref mut interest,
-        } = *lock;
-
-        inn.push_back(t);
-        debug_assert!(!inn.is_empty());
-        trace!("MailboxOuterEnd: putting message in a queue; new len: {}",
-               inn.len());
+    pub fn send_at(&self, deadline: SteadyTime, t: T) -> DelayedSend<T> {
+        let slot = Arc::new(Mutex::new(Some(t)));
+        let outer = self.clone();
+        let deliver_slot = slot.clone();
+        let deliver = move || {
+            if let Some(t) = deliver_slot.lock().take() {
+                let _ = outer.send(t);
+            }
+        };
 
-        if interest.is_readable() {
-            let token = token.unwrap();
-            trace!("MailboxOuterEnd: notifying {:?}", token);
-            let sender = sender.as_ref().unwrap();
-            sender_retry(&sender, Message::MailboxMsg(token))
+        let timer = if in_coroutine() {
+            let sender = tl_coroutine_current().handler_shared().get_sender_to_own_thread();
+            let id = NEXT_RUN_ID.fetch_add(1, Ordering::SeqCst);
+            sender_retry(&sender, Message::RunAt(id, deadline, Box::new(deliver)));
+            DelayedTimer::EventLoop(sender, id)
+        } else {
+            DelayedTimer::Thread(DelayedQueue::push(&DELAYED_QUEUE, deadline, Box::new(deliver)))
+        };
+
+        DelayedSend {
+            slot: slot,
+            timer: timer,
+        }
+    }
+}
+
+/// Id of the next `Message::RunAt` of a delayed delivery
+static NEXT_RUN_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Delivery on the shared timer thread: deadline and scheduling order
+type DelayedKey = (SteadyTime, u64);
+
+struct DelayedShared {
+    entries: BTreeMap<DelayedKey, Box<FnBox() + Send>>,
+    /// Sequence number of the next entry
+    next_seq: u64,
+    /// Timer thread is running
+    running: bool,
+}
+
+/// Timer thread making `send_at()` deliveries requested outside of
+/// coroutines
+///
+/// Started when a delivery is scheduled; exits when none are left.
+struct DelayedQueue {
+    shared: StdMutex<DelayedShared>,
+    cond: Condvar,
+}
+
+lazy_static! {
+    static ref DELAYED_QUEUE: Arc<DelayedQueue> = Arc::new(DelayedQueue {
+        shared: StdMutex::new(DelayedShared {
+            entries: BTreeMap::new(),
+            next_seq: 0,
+            running: false,
+        }),
+        cond: Condvar::new(),
+    });
+}
+
+impl DelayedQueue {
+    fn push(queue: &Arc<DelayedQueue>,
+            deadline: SteadyTime,
+            deliver: Box<FnBox() + Send>)
+            -> DelayedKey {
+        let mut lock = queue.shared.lock().unwrap();
+        let key = (deadline, lock.next_seq);
+        lock.next_seq += 1;
+        lock.entries.insert(key, deliver);
+
+        if lock.running {
+            queue.cond.notify_one();
+        } else {
+            lock.running = true;
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name("mioco_delayed_send".to_owned())
+                .spawn(move || queue.run())
+                .expect("Couldn't spawn delayed send thread");
+        }
+        key
+    }
+
+    fn cancel(&self, key: &DelayedKey) {
+        let deliver = self.shared.lock().unwrap().entries.remove(key);
+        if deliver.is_some() {
+            self.cond.notify_one();
+        }
+    }
+
+    fn run(&self) {
+        let mut lock = self.shared.lock().unwrap();
+        loop {
+            let now = timer::now();
+            let first = lock.entries.keys().next().cloned();
+            let (deadline, seq) = match first {
+                Some(key) => key,
+                None => {
+                    trace!("DelayedQueue: no deliveries left; exiting");
+                    lock.running = false;
+                    return;
+                }
+            };
+
+            if deadline <= now {
+                let deliver = lock.entries.remove(&(deadline, seq)).unwrap();
+                drop(lock);
+                deliver();
+                lock = self.shared.lock().unwrap();
+            } else {
+                let delay_us = (deadline - now).num_microseconds().unwrap_or(i64::max_value());
+                let delay = StdDuration::new(delay_us as u64 / 1_000_000,
+                                             (delay_us % 1_000_000) as u32 * 1000);
+                lock = self.cond.wait_timeout(lock, delay).unwrap().0;
+            }
+        }
+    }
+}
+
+/// Handle of a pending delayed delivery
+///
+/// Returned by `MailboxOuterEnd::send_at()` and `send_after()`. Dropping
+/// it does not cancel the delivery.
+pub struct DelayedSend<T> {
+    slot: Arc<Mutex<Option<T>>>,
+    timer: DelayedTimer,
+}
+
+/// Where a delayed delivery is scheduled
+enum DelayedTimer {
+    /// Event loop of a mioco thread, by `Message::RunAt` id
+    EventLoop(MioSender, usize),
+    /// The shared timer thread
+    Thread(DelayedKey),
+}
+
+impl<T> DelayedSend<T> {
+    /// Cancel the delivery.
+    ///
+    /// Returns the message if it was not delivered yet. The scheduled
+    /// delivery is removed right away, not left until its deadline.
+    pub fn cancel(self) -> Option<T> {
+        let t = self.slot.lock().take();
+        if t.is_some() {
+            match self.timer {
+                DelayedTimer::EventLoop(ref sender, id) => {
+                    // A finished mioco instance dropped its timers already
+                    if let Err(NotifyError::Full(msg)) = sender.send(Message::CancelRun(id)) {
+                        sender_retry(sender, msg);
+                    }
+                }
+                DelayedTimer::Thread(ref key) => DELAYED_QUEUE.cancel(key),
+            }
         }
+        t
+    }
+
+    /// Is the delivery still pending?
+    pub fn is_pending(&self) -> bool {
+        self.slot.lock().is_some()
     }
 }
 
@@ -142,50 +625,1833 @@ impl<T> MailboxInnerEnd<T> where T: 'static
 {
     /// Receive `T` sent using corresponding `MailboxOuterEnd::send()`.
     ///
-    /// Will block coroutine if no elements are available.
-    pub fn read(&self) -> T {
+    /// Will block coroutine (or park the thread, outside of coroutines) if
+    /// no elements are available. Fails once the mailbox is empty and all
+    /// sending ends were dropped.
+    pub fn read(&self) -> Result<T, RecvError> {
         loop {
-            if let Some(t) = self.try_read() {
-                return t;
+            match self.try_read() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
             }
 
-            self.block_on(RW::read())
+            if in_coroutine() {
+                self.block_on(RW::read())
+            } else if self.park_prepare() {
+                thread::park();
+            }
+        }
+    }
+
+    /// Like `read()`, but gives up after `timeout_ms`.
+    pub fn read_timeout(&self, timeout_ms: i64) -> Result<T, RecvTimeoutError> {
+        if in_coroutine() {
+            let mut timer = Timer::new();
+            timer.set_timeout(timeout_ms);
+
+            loop {
+                match self.try_read() {
+                    Ok(t) => return Ok(t),
+                    Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                    Err(TryRecvError::Empty) => {}
+                }
+                if timer.try_read().is_some() {
+                    return Err(RecvTimeoutError::Timeout);
+                }
+
+                unsafe {
+                    self.select_add(RW::read());
+                    timer.select_add(RW::read());
+                }
+                let _ = select_wait();
+            }
+        }
+
+        let deadline = SteadyTime::now() + Duration::milliseconds(timeout_ms);
+        loop {
+            match self.try_read() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            let remaining = (deadline - SteadyTime::now()).num_milliseconds();
+            if remaining <= 0 {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            if self.park_prepare() {
+                thread::park_timeout(StdDuration::from_millis(remaining as u64));
+            }
+        }
+    }
+
+    /// Receive up to `max` messages at once.
+    ///
+    /// Blocks like `read()` only if the mailbox is empty; otherwise returns
+    /// immediately with messages already waiting.
+    pub fn read_batch(&self, max: usize) -> Result<Vec<T>, RecvError> {
+        assert!(max > 0);
+        loop {
+            match self.try_read_batch(max) {
+                Ok(batch) => return Ok(batch),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            if in_coroutine() {
+                self.block_on(RW::read())
+            } else if self.park_prepare() {
+                thread::park();
+            }
+        }
+    }
+
+    /// Take all messages waiting in the mailbox.
+    ///
+    /// This will not block. Returns an empty `Vec` if there are none.
+    pub fn drain(&self) -> Vec<T> {
+        self.try_read_batch(usize::max_value()).unwrap_or(Vec::new())
+    }
+
+    fn try_read_batch(&self, max: usize) -> Result<Vec<T>, TryRecvError> {
+        let shared = self.shared();
+        let io_ref = shared.io_ref();
+        let shared = &io_ref.0;
+
+        let batch = shared.pop_batch(max);
+        if !batch.is_empty() {
+            Ok(batch)
+        } else if shared.senders.load(Ordering::SeqCst) == 0 {
+            Err(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    /// Register current thread to be unparked on new message.
+    ///
+    /// Returns `false` if there's no need to park.
+    fn park_prepare(&self) -> bool {
+        let shared = self.shared();
+        let io_ref = shared.io_ref();
+        let mut waiters = io_ref.0.waiters.lock();
+
+        waiters.parked_receiver = Some(thread::current());
+        if io_ref.0.receiver_wait() {
+            waiters.parked_receiver = None;
+            return false;
         }
+        true
+    }
+
+    /// Undo `park_prepare`, if no notification consumed it.
+    fn park_cancel(&self) {
+        let shared = self.shared();
+        let io_ref = shared.io_ref();
+        let mut waiters = io_ref.0.waiters.lock();
+
+        io_ref.0.receiver_waiting.store(false, Ordering::SeqCst);
+        waiters.parked_receiver = None;
     }
 
     /// Try reading data from the queue.
     ///
     /// This will not block.
-    pub fn try_read(&self) -> Option<T> {
+    pub fn try_read(&self) -> Result<T, TryRecvError> {
         let shared = self.shared();
         let io_ref = shared.io_ref();
         let shared = &io_ref.0;
-        let mut lock = shared.lock();
 
-        lock.inn.pop_front()
+        match shared.pop() {
+            Some(t) => Ok(t),
+            None if shared.senders.load(Ordering::SeqCst) == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
     }
 }
 
-/// Create a Mailbox
-///
-/// Mailbox can be used to deliver notifications to handlers from anywhere:
+impl<T> MailboxInnerEnd<T> where T: 'static
+{
+    /// Iterate over received messages.
+    ///
+    /// Blocks like `read()`; ends when the mailbox is disconnected. All
+    /// the standard iterator adapters (`map()`, `filter()`, ...) work on
+    /// it as usual.
+    pub fn iter(&self) -> Iter<T> {
+        Iter { inner: self }
+    }
+
+    /// Iterate over messages received from both `self` and `other`.
+    ///
+    /// Blocks until either of mailboxes has a message; ends when both are
+    /// disconnected.
+    pub fn merge<'a>(&'a self, other: &'a MailboxInnerEnd<T>) -> Merge<'a, T> {
+        Merge {
+            inners: [self, other],
+            disconnected: [false, false],
+            first: 0,
+        }
+    }
+
+    /// Iterate over received messages, reporting `timeout_ms` long gaps.
+    ///
+    /// Yields `Err(RecvTimeoutError::Timeout)` whenever there was no
+    /// message for `timeout_ms`; ends when the mailbox is disconnected.
+    pub fn timeout(&self, timeout_ms: i64) -> Timeouts<T> {
+        Timeouts {
+            inner: self,
+            timeout_ms: timeout_ms,
+        }
+    }
+
+    /// Iterate over received messages in chunks.
+    ///
+    /// A chunk is yielded once it has `n` messages, or `max_wait_ms` after
+    /// its first message was received, whichever happens first. Ends when
+    /// the mailbox is disconnected, after yielding the last, possibly
+    /// incomplete, chunk.
+    pub fn chunks(&self, n: usize, max_wait_ms: i64) -> Chunks<T> {
+        assert!(n > 0);
+        Chunks {
+            inner: self,
+            n: n,
+            max_wait_ms: max_wait_ms,
+            disconnected: false,
+        }
+    }
+}
+
+/// Iterator over messages of a mailbox
 ///
-/// * other coroutines,
-/// * outside of Mioco, even a different thread.
+/// Create with `MailboxInnerEnd::iter()`
+pub struct Iter<'a, T: 'a> {
+    inner: &'a MailboxInnerEnd<T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> where T: 'static
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.inner.read().ok()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a MailboxInnerEnd<T> where T: 'static
+{
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+/// Owning iterator over messages of a mailbox
+pub struct IntoIter<T> {
+    inner: MailboxInnerEnd<T>,
+}
+
+impl<T> Iterator for IntoIter<T> where T: 'static
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.inner.read().ok()
+    }
+}
+
+impl<T> IntoIterator for MailboxInnerEnd<T> where T: 'static
+{
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { inner: self }
+    }
+}
+
+/// Iterator over messages of two mailboxes
 ///
-pub fn mailbox<T>() -> (MailboxOuterEnd<T>, MailboxInnerEnd<T>) {
-    let shared = MailboxShared {
-        token: None,
-        sender: None,
-        inn: VecDeque::new(),
-        interest: EventSet::none(),
-    };
+/// Create with `MailboxInnerEnd::merge()`
+pub struct Merge<'a, T: 'a> {
+    inners: [&'a MailboxInnerEnd<T>; 2],
+    disconnected: [bool; 2],
+    /// Mailbox to try first, alternated for fairness
+    first: usize,
+}
 
-    let shared = Arc::new(Mutex::new(shared));
+impl<'a, T> Iterator for Merge<'a, T> where T: 'static
+{
+    type Item = T;
 
-    (MailboxOuterEnd::new(shared.clone()),
-     MailboxInnerEnd::new(shared))
+    fn next(&mut self) -> Option<T> {
+        loop {
+            for i in [self.first, 1 - self.first].iter().cloned() {
+                if self.disconnected[i] {
+                    continue;
+                }
+                match self.inners[i].try_read() {
+                    Ok(t) => {
+                        self.first = 1 - i;
+                        return Some(t);
+                    }
+                    Err(TryRecvError::Disconnected) => self.disconnected[i] = true,
+                    Err(TryRecvError::Empty) => {}
+                }
+            }
+
+            if self.disconnected[0] && self.disconnected[1] {
+                return None;
+            }
+
+            let waiting: Vec<&MailboxInnerEnd<T>> = (0..2)
+                                                        .filter(|&i| !self.disconnected[i])
+                                                        .map(|i| self.inners[i])
+                                                        .collect();
+            if in_coroutine() {
+                for inner in &waiting {
+                    unsafe {
+                        inner.select_add(RW::read());
+                    }
+                }
+                let _ = select_wait();
+            } else {
+                // Prepare every mailbox, even if an earlier one is ready,
+                // and cancel them all afterwards, so none is left
+                // registered to notify this thread.
+                let prepared: Vec<bool> = waiting.iter()
+                                                 .map(|inner| inner.park_prepare())
+                                                 .collect();
+                if prepared.iter().all(|&p| p) {
+                    thread::park();
+                }
+                for inner in &waiting {
+                    inner.park_cancel();
+                }
+            }
+        }
+    }
 }
 
+/// Iterator over messages of a mailbox, reporting gaps
+///
+/// Create with `MailboxInnerEnd::timeout()`
+pub struct Timeouts<'a, T: 'a> {
+    inner: &'a MailboxInnerEnd<T>,
+    timeout_ms: i64,
+}
+
+impl<'a, T> Iterator for Timeouts<'a, T> where T: 'static
+{
+    type Item = Result<T, RecvTimeoutError>;
+
+    fn next(&mut self) -> Option<Result<T, RecvTimeoutError>> {
+        match self.inner.read_timeout(self.timeout_ms) {
+            Ok(t) => Some(Ok(t)),
+            Err(RecvTimeoutError::Timeout) => Some(Err(RecvTimeoutError::Timeout)),
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+/// Iterator over chunks of messages of a mailbox
+///
+/// Create with `MailboxInnerEnd::chunks()`
+pub struct Chunks<'a, T: 'a> {
+    inner: &'a MailboxInnerEnd<T>,
+    n: usize,
+    max_wait_ms: i64,
+    disconnected: bool,
+}
+
+impl<'a, T> Iterator for Chunks<'a, T> where T: 'static
+{
+    type Item = Vec<T>;
+
+    fn next(&mut self) -> Option<Vec<T>> {
+        if self.disconnected {
+            return None;
+        }
+
+        let mut chunk = match self.inner.read_batch(self.n) {
+            Ok(batch) => batch,
+            Err(RecvError) => return None,
+        };
+
+        let clock = Clock::current();
+        let deadline = clock.now() + Duration::milliseconds(self.max_wait_ms);
+        while chunk.len() < self.n {
+            let remaining = (deadline - clock.now()).num_milliseconds();
+            if remaining <= 0 {
+                break;
+            }
+            match self.inner.read_timeout(remaining) {
+                Ok(t) => chunk.push(t),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.disconnected = true;
+                    break;
+                }
+            }
+            let rest = self.n - chunk.len();
+            if let Ok(batch) = self.inner.try_read_batch(rest) {
+                chunk.extend(batch);
+            }
+        }
+
+        Some(chunk)
+    }
+}
+
+/// Create a Mailbox
+///
+/// Mailbox can be used to deliver notifications to handlers from anywhere:
+///
+/// * other coroutines,
+/// * outside of Mioco, even a different thread.
+///
+pub fn mailbox<T>() -> (MailboxOuterEnd<T>, MailboxInnerEnd<T>) {
+    let shared = Arc::new(MailboxShared::new(None));
+
+    (MailboxOuterEnd::new(shared.clone()),
+     MailboxInnerEnd::new(shared))
+}
+
+/// Create a bounded Mailbox
+///
+/// Like `mailbox()`, but holding at most `capacity` messages: sending to
+/// a full mailbox blocks until the receiving end reads something.
+pub fn bounded<T>(capacity: usize) -> (BoundedMailboxOuterEnd<T>, MailboxInnerEnd<T>) {
+    assert!(capacity > 0);
+    let shared = Arc::new(MailboxShared::new(Some(capacity)));
+
+    (BoundedMailboxOuterEnd::new(shared.clone()),
+     MailboxInnerEnd::new(shared))
+}
+
+/// Error returned by `BoundedMailboxOuterEnd::try_send()`
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// Mailbox is full; the value is returned
+    Full(T),
+    /// Receiving end was dropped; the value is returned
+    Disconnected(T),
+}
+
+static NEXT_SENDER_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Sender waiting for free space in a bounded mailbox, with its id
+enum BlockedSender {
+    Coroutine(usize, Waker),
+    Thread(usize, thread::Thread),
+}
+
+impl BlockedSender {
+    fn id(&self) -> usize {
+        match *self {
+            BlockedSender::Coroutine(id, _) |
+            BlockedSender::Thread(id, _) => id,
+        }
+    }
+
+    fn wake(self) {
+        match self {
+            BlockedSender::Coroutine(id, mut waker) => {
+                trace!("BoundedMailboxOuterEnd: waking sender {}", id);
+                waker.wake(EventSet::writable());
+            }
+            BlockedSender::Thread(id, thread) => {
+                trace!("BoundedMailboxOuterEnd: unparking sender {}", id);
+                thread.unpark();
+            }
+        }
+    }
+}
+
+/// Bounded mailbox sending end
+///
+/// Sends block when the mailbox is full: coroutines are blocked,
+/// threads outside of mioco are parked. Inside coroutines it is an event
+/// source, ready for writing when there is free space, so it can be used
+/// in `select!`.
+///
+/// Create with `bounded()`
+pub struct BoundedMailboxOuterEnd<T>(RcEventSource<BoundedMailboxOuterCore<T>>);
+
+struct BoundedMailboxOuterCore<T> {
+    shared: ArcMailboxShared<T>,
+    /// Identifies registration in `MailboxWaiters::blocked_senders`
+    id: usize,
+    /// Coroutine is being resumed by this sender (see `deregister()`)
+    resuming: Cell<bool>,
+}
+
+impl<T> BoundedMailboxOuterEnd<T> {
+    fn new(shared: ArcMailboxShared<T>) -> Self {
+        shared.sender_added();
+        BoundedMailboxOuterEnd(RcEventSource::new(BoundedMailboxOuterCore {
+            shared: shared,
+            id: NEXT_SENDER_ID.fetch_add(1, Ordering::Relaxed),
+            resuming: Cell::new(false),
+        }))
+    }
+}
+
+impl<T> Clone for BoundedMailboxOuterEnd<T> {
+    fn clone(&self) -> Self {
+        BoundedMailboxOuterEnd::new(self.0.io_ref().shared.clone())
+    }
+}
+
+impl<T> EventedImpl for BoundedMailboxOuterEnd<T> where T: 'static
+{
+    type Raw = BoundedMailboxOuterCore<T>;
+
+    fn shared(&self) -> &RcEventSource<BoundedMailboxOuterCore<T>> {
+        &self.0
+    }
+}
+
+impl<T> EventSourceTrait for BoundedMailboxOuterCore<T> {
+    fn register(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        trace!("BoundedMailboxOuterEnd({}): register", token.as_usize());
+        let mut waker = Waker::new();
+        waker.register(event_loop, token, interest);
+
+        let id = self.id;
+        let to_wake = {
+            let mut waiters = self.shared.waiters.lock();
+            waiters.blocked_senders.retain(|sender| sender.id() != id);
+            waiters.woken_senders.remove(&id);
+            waiters.blocked_senders.push_back(BlockedSender::Coroutine(id, waker));
+            self.shared.dispatch_senders(&mut waiters)
+        };
+        for sender in to_wake {
+            sender.wake();
+        }
+    }
+
+    fn reregister(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        self.register(event_loop, token, interest)
+    }
+
+    fn deregister(&self, _event_loop: &mut EventLoop<Handler>, token: Token) {
+        trace!("BoundedMailboxOuterEnd({}): deregister", token.as_usize());
+        let id = self.id;
+        let to_wake = {
+            let mut waiters = self.shared.waiters.lock();
+            waiters.blocked_senders.retain(|sender| sender.id() != id);
+            // Woken up, but resumed by another event source: the free slot
+            // would be left unclaimed, so pass it on.
+            if waiters.woken_senders.remove(&id) && !self.resuming.get() {
+                self.shared.dispatch_senders(&mut waiters)
+            } else {
+                Vec::new()
+            }
+        };
+        self.resuming.set(false);
+        for sender in to_wake {
+            sender.wake();
+        }
+    }
+
+    fn should_resume(&self) -> bool {
+        self.shared.is_writable()
+    }
+
+    fn on_resume(&self) {
+        self.resuming.set(true);
+    }
+}
+
+impl<T> Drop for BoundedMailboxOuterCore<T> {
+    fn drop(&mut self) {
+        self.shared.sender_dropped();
+    }
+}
+
+impl<T> BoundedMailboxOuterEnd<T> where T: 'static
+{
+    /// Deliver `T` to the other end of the mailbox.
+    ///
+    /// Blocks while the mailbox is full. Works both inside and outside of
+    /// coroutines. Fails if the receiving end was dropped.
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        let mut t = t;
+        loop {
+            t = match self.try_send(t) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(t)) => t,
+                Err(TrySendError::Disconnected(t)) => return Err(SendError(t)),
+            };
+
+            if in_coroutine() {
+                self.block_on(RW::write());
+            } else {
+                let io_ref = self.0.io_ref();
+                let id = io_ref.id;
+                let to_wake = {
+                    let mut waiters = io_ref.shared.waiters.lock();
+                    waiters.blocked_senders.push_back(BlockedSender::Thread(id, thread::current()));
+                    io_ref.shared.dispatch_senders(&mut waiters)
+                };
+                for sender in to_wake {
+                    sender.wake();
+                }
+
+                thread::park();
+
+                let mut waiters = io_ref.shared.waiters.lock();
+                waiters.blocked_senders.retain(|sender| sender.id() != id);
+                waiters.woken_senders.remove(&id);
+            }
+        }
+    }
+
+    /// Try delivering `T` to the other end of the mailbox.
+    ///
+    /// This will not block. If the mailbox is full or the receiving end was
+    /// dropped, `t` is returned back.
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        let io_ref = self.0.io_ref();
+        let shared = &io_ref.shared;
+
+        if !shared.receiver_alive.load(Ordering::SeqCst) {
+            return Err(TrySendError::Disconnected(t));
+        }
+
+        shared.try_push(t).map_err(TrySendError::Full)
+    }
+}
+
+unsafe impl<T> Send for BoundedMailboxOuterEnd<T> {}
+
 
 unsafe impl<T> Send for MailboxInnerEnd<T> {}
+
+/// What to do when a broadcast subscriber's buffer is full
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Silently drop the oldest message
+    DropOldest,
+    /// Drop the oldest message, and report how many were dropped with the
+    /// next read (see `BroadcastRecvError::Lagged`)
+    Report,
+}
+
+/// Error returned by `BroadcastReceiver::read()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BroadcastRecvError {
+    /// Subscriber was too slow and given number of messages were dropped;
+    /// reading can continue
+    Lagged(u64),
+    /// No messages buffered and all sending ends were dropped
+    Disconnected,
+}
+
+/// Error returned by `BroadcastReceiver::try_read()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BroadcastTryRecvError {
+    /// No messages buffered
+    Empty,
+    /// See `BroadcastRecvError::Lagged`
+    Lagged(u64),
+    /// See `BroadcastRecvError::Disconnected`
+    Disconnected,
+}
+
+struct BroadcastShared<T> {
+    subscribers: Vec<(usize, Arc<Mutex<Subscription<T>>>)>,
+    senders: usize,
+    capacity: usize,
+    policy: LagPolicy,
+}
+
+struct Subscription<T> {
+    queue: VecDeque<T>,
+    lagged: u64,
+    disconnected: bool,
+    waker: Waker,
+}
+
+impl<T> Subscription<T> {
+    fn is_readable(&self) -> bool {
+        !self.queue.is_empty() || self.lagged > 0 || self.disconnected
+    }
+}
+
+static NEXT_SUBSCRIBER_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Create a broadcast channel
+///
+/// Every message sent is delivered to every subscriber (see
+/// `BroadcastSender::subscribe()`) subscribed at the time. Each
+/// subscriber buffers at most `capacity` messages; what happens to slow
+/// subscribers is decided by `policy`. Sending never blocks.
+pub fn broadcast<T: Clone>(capacity: usize, policy: LagPolicy) -> BroadcastSender<T> {
+    assert!(capacity > 0);
+    let shared = Arc::new(Mutex::new(BroadcastShared {
+        subscribers: Vec::new(),
+        senders: 0,
+        capacity: capacity,
+        policy: policy,
+    }));
+
+    BroadcastSender::new(shared)
+}
+
+/// Broadcast channel sending end
+///
+/// Use this inside mioco coroutines or outside of mioco itself.
+///
+/// Create with `broadcast()`
+pub struct BroadcastSender<T> {
+    shared: Arc<Mutex<BroadcastShared<T>>>,
+}
+
+impl<T> BroadcastSender<T> {
+    fn new(shared: Arc<Mutex<BroadcastShared<T>>>) -> Self {
+        shared.lock().senders += 1;
+        BroadcastSender { shared: shared }
+    }
+}
+
+impl<T> Clone for BroadcastSender<T> {
+    fn clone(&self) -> Self {
+        BroadcastSender::new(self.shared.clone())
+    }
+}
+
+impl<T> Drop for BroadcastSender<T> {
+    fn drop(&mut self) {
+        let mut lock = self.shared.lock();
+        lock.senders -= 1;
+        if lock.senders == 0 {
+            for &(_, ref subscription) in lock.subscribers.iter() {
+                let mut subscription = subscription.lock();
+                subscription.disconnected = true;
+                subscription.waker.wake(EventSet::readable());
+            }
+        }
+    }
+}
+
+impl<T: Clone> BroadcastSender<T> {
+    /// Deliver `t` to all current subscribers.
+    ///
+    /// Returns number of subscribers it was delivered to. This is
+    /// non-blocking operation.
+    pub fn send(&self, t: T) -> usize {
+        // Dropped after unlocking: they might hold senders of this channel
+        let mut evicted = Vec::new();
+        let lock = self.shared.lock();
+        for &(_, ref subscription) in lock.subscribers.iter() {
+            let mut subscription = subscription.lock();
+            if subscription.queue.len() >= lock.capacity {
+                evicted.extend(subscription.queue.pop_front());
+                if lock.policy == LagPolicy::Report {
+                    subscription.lagged += 1;
+                }
+            }
+            subscription.queue.push_back(t.clone());
+            subscription.waker.wake(EventSet::readable());
+        }
+        let subscribers = lock.subscribers.len();
+        drop(lock);
+        drop(evicted);
+        subscribers
+    }
+
+    /// Create a new subscriber.
+    ///
+    /// It will receive messages sent from now on.
+    pub fn subscribe(&self) -> BroadcastReceiver<T> {
+        let subscription = Arc::new(Mutex::new(Subscription {
+            queue: VecDeque::new(),
+            lagged: 0,
+            disconnected: false,
+            waker: Waker::new(),
+        }));
+        let id = NEXT_SUBSCRIBER_ID.fetch_add(1, Ordering::Relaxed);
+        self.shared.lock().subscribers.push((id, subscription.clone()));
+
+        BroadcastReceiver(RcEventSource::new(BroadcastReceiverCore {
+            shared: self.shared.clone(),
+            subscription: subscription,
+            id: id,
+        }))
+    }
+
+    /// Number of current subscribers.
+    pub fn subscribers(&self) -> usize {
+        self.shared.lock().subscribers.len()
+    }
+}
+
+/// Broadcast channel subscriber
+///
+/// Use this only inside mioco coroutines, as an asynchronous event source.
+/// Dropping it unsubscribes.
+///
+/// Create with `BroadcastSender::subscribe()`
+pub struct BroadcastReceiver<T>(RcEventSource<BroadcastReceiverCore<T>>);
+
+struct BroadcastReceiverCore<T> {
+    shared: Arc<Mutex<BroadcastShared<T>>>,
+    subscription: Arc<Mutex<Subscription<T>>>,
+    id: usize,
+}
+
+impl<T> EventedImpl for BroadcastReceiver<T> where T: 'static
+{
+    type Raw = BroadcastReceiverCore<T>;
+
+    fn shared(&self) -> &RcEventSource<BroadcastReceiverCore<T>> {
+        &self.0
+    }
+}
+
+impl<T> EventSourceTrait for BroadcastReceiverCore<T> {
+    fn register(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        trace!("BroadcastReceiver({}): register", token.as_usize());
+        let mut lock = self.subscription.lock();
+        lock.waker.register(event_loop, token, interest);
+
+        if lock.is_readable() {
+            trace!("BroadcastReceiver({}): ready; self notify", token.as_usize());
+            lock.waker.wake(EventSet::readable());
+        }
+    }
+
+    fn reregister(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        self.register(event_loop, token, interest)
+    }
+
+    fn deregister(&self, _event_loop: &mut EventLoop<Handler>, token: Token) {
+        trace!("BroadcastReceiver({}): deregister", token.as_usize());
+        self.subscription.lock().waker.deregister();
+    }
+
+    fn should_resume(&self) -> bool {
+        self.subscription.lock().is_readable()
+    }
+}
+
+impl<T> Drop for BroadcastReceiverCore<T> {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.shared.lock().subscribers.retain(|&(subscriber_id, _)| subscriber_id != id);
+    }
+}
+
+impl<T> BroadcastReceiver<T> where T: 'static
+{
+    /// Receive next broadcast message.
+    ///
+    /// Will block coroutine if no messages are available.
+    pub fn read(&self) -> Result<T, BroadcastRecvError> {
+        loop {
+            match self.try_read() {
+                Ok(t) => return Ok(t),
+                Err(BroadcastTryRecvError::Lagged(n)) => return Err(BroadcastRecvError::Lagged(n)),
+                Err(BroadcastTryRecvError::Disconnected) => {
+                    return Err(BroadcastRecvError::Disconnected)
+                }
+                Err(BroadcastTryRecvError::Empty) => {}
+            }
+
+            self.block_on(RW::read())
+        }
+    }
+
+    /// Try receiving next broadcast message.
+    ///
+    /// This will not block.
+    pub fn try_read(&self) -> Result<T, BroadcastTryRecvError> {
+        let io_ref = self.0.io_ref();
+        let mut lock = io_ref.subscription.lock();
+
+        if lock.lagged > 0 {
+            let lagged = lock.lagged;
+            lock.lagged = 0;
+            return Err(BroadcastTryRecvError::Lagged(lagged));
+        }
+
+        match lock.queue.pop_front() {
+            Some(t) => Ok(t),
+            None if lock.disconnected => Err(BroadcastTryRecvError::Disconnected),
+            None => Err(BroadcastTryRecvError::Empty),
+        }
+    }
+}
+
+unsafe impl<T> Send for BroadcastReceiver<T> {}
+
+struct OneshotShared<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    waker: Waker,
+}
+
+impl<T> OneshotShared<T> {
+    fn is_readable(&self) -> bool {
+        self.value.is_some() || !self.sender_alive
+    }
+}
+
+/// Create a one-shot channel
+///
+/// A channel for exactly one value, typically a reply to a request.
+/// Cheaper than a `mailbox()`.
+pub fn oneshot<T>() -> (OneshotSender<T>, OneshotReceiver<T>) {
+    let shared = Arc::new(Mutex::new(OneshotShared {
+        value: None,
+        sender_alive: true,
+        receiver_alive: true,
+        waker: Waker::new(),
+    }));
+
+    (OneshotSender { shared: shared.clone() },
+     OneshotReceiver(RcEventSource::new(OneshotReceiverCore(shared))))
+}
+
+/// One-shot channel sending end
+///
+/// Use this inside mioco coroutines or outside of mioco itself.
+///
+/// Create with `oneshot()`
+pub struct OneshotSender<T> {
+    shared: Arc<Mutex<OneshotShared<T>>>,
+}
+
+impl<T> OneshotSender<T> {
+    /// Deliver `t` to the receiving end.
+    ///
+    /// This is non-blocking operation. Fails if the receiving end was
+    /// dropped.
+    pub fn send(self, t: T) -> Result<(), SendError<T>> {
+        let mut lock = self.shared.lock();
+        if !lock.receiver_alive {
+            return Err(SendError(t));
+        }
+        lock.value = Some(t);
+        lock.waker.wake(EventSet::readable());
+        Ok(())
+    }
+}
+
+impl<T> Drop for OneshotSender<T> {
+    fn drop(&mut self) {
+        let mut lock = self.shared.lock();
+        lock.sender_alive = false;
+        lock.waker.wake(EventSet::readable());
+    }
+}
+
+/// One-shot channel receiving end
+///
+/// Use this only inside mioco coroutines, as an asynchronous event source.
+///
+/// Create with `oneshot()`
+pub struct OneshotReceiver<T>(RcEventSource<OneshotReceiverCore<T>>);
+
+struct OneshotReceiverCore<T>(Arc<Mutex<OneshotShared<T>>>);
+
+impl<T> EventedImpl for OneshotReceiver<T> where T: 'static
+{
+    type Raw = OneshotReceiverCore<T>;
+
+    fn shared(&self) -> &RcEventSource<OneshotReceiverCore<T>> {
+        &self.0
+    }
+}
+
+impl<T> EventSourceTrait for OneshotReceiverCore<T> {
+    fn register(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        trace!("OneshotReceiver({}): register", token.as_usize());
+        let mut lock = self.0.lock();
+        lock.waker.register(event_loop, token, interest);
+
+        if lock.is_readable() {
+            trace!("OneshotReceiver({}): ready; self notify", token.as_usize());
+            lock.waker.wake(EventSet::readable());
+        }
+    }
+
+    fn reregister(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        self.register(event_loop, token, interest)
+    }
+
+    fn deregister(&self, _event_loop: &mut EventLoop<Handler>, token: Token) {
+        trace!("OneshotReceiver({}): deregister", token.as_usize());
+        self.0.lock().waker.deregister();
+    }
+
+    fn should_resume(&self) -> bool {
+        self.0.lock().is_readable()
+    }
+}
+
+impl<T> Drop for OneshotReceiverCore<T> {
+    fn drop(&mut self) {
+        let mut lock = self.0.lock();
+        lock.receiver_alive = false;
+        lock.value = None;
+    }
+}
+
+impl<T> OneshotReceiver<T> where T: 'static
+{
+    /// Receive the value.
+    ///
+    /// Will block coroutine until the value is sent. Fails if the sending
+    /// end was dropped without sending anything.
+    pub fn read(&self) -> Result<T, RecvError> {
+        loop {
+            match self.try_read() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            self.block_on(RW::read())
+        }
+    }
+
+    /// Try receiving the value.
+    ///
+    /// This will not block.
+    pub fn try_read(&self) -> Result<T, TryRecvError> {
+        let io_ref = self.0.io_ref();
+        let mut lock = io_ref.0.lock();
+
+        match lock.value.take() {
+            Some(t) => Ok(t),
+            None if !lock.sender_alive => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+unsafe impl<T> Send for OneshotReceiver<T> {}
+
+/// Request received from a `Requester`
+///
+/// Carries the sending end for the reply.
+pub struct Request<Req, Resp> {
+    /// Request itself
+    pub request: Req,
+    reply: OneshotSender<Resp>,
+}
+
+impl<Req, Resp> Request<Req, Resp> {
+    /// Send a reply to the caller.
+    ///
+    /// Fails if the caller is not waiting anymore (eg. timed out).
+    /// Dropping a `Request` without replying makes the call fail.
+    pub fn reply(self, resp: Resp) -> Result<(), SendError<Resp>> {
+        self.reply.send(resp)
+    }
+}
+
+/// Error returned by `Requester::call()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CallError {
+    /// The receiving end of requests was dropped, or the request was
+    /// dropped without a reply
+    Disconnected,
+    /// No reply in time (only from `call_timeout()`)
+    TimedOut,
+}
+
+/// Request/response helper
+///
+/// Sends `Request`s to a service coroutine reading them from a
+/// `MailboxInnerEnd`, and waits for replies.
+///
+/// Create with `requester()`
+pub struct Requester<Req, Resp> {
+    mail: MailboxOuterEnd<Request<Req, Resp>>,
+}
+
+impl<Req, Resp> Clone for Requester<Req, Resp> {
+    fn clone(&self) -> Self {
+        Requester { mail: self.mail.clone() }
+    }
+}
+
+/// Create a request/response channel
+///
+/// The service coroutine reads `Request`s from the returned mailbox and
+/// answers them with `Request::reply()`.
+pub fn requester<Req, Resp>() -> (Requester<Req, Resp>, MailboxInnerEnd<Request<Req, Resp>>) {
+    let (mail_send, mail_recv) = mailbox();
+    (Requester { mail: mail_send }, mail_recv)
+}
+
+impl<Req, Resp> Requester<Req, Resp> where Resp: 'static
+{
+    /// Send a request and block coroutine until the reply arrives.
+    pub fn call(&self, request: Req) -> Result<Resp, CallError> {
+        let reply = try!(self.send(request));
+        reply.read().map_err(|_| CallError::Disconnected)
+    }
+
+    /// Like `call()`, but gives up after `timeout_ms`.
+    pub fn call_timeout(&self, request: Req, timeout_ms: i64) -> Result<Resp, CallError> {
+        let reply = try!(self.send(request));
+
+        let mut timer = Timer::new();
+        timer.set_timeout(timeout_ms);
+
+        loop {
+            match reply.try_read() {
+                Ok(resp) => return Ok(resp),
+                Err(TryRecvError::Disconnected) => return Err(CallError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            if timer.try_read().is_some() {
+                return Err(CallError::TimedOut);
+            }
+
+            unsafe {
+                reply.select_add(RW::read());
+                timer.select_add(RW::read());
+            }
+            let _ = select_wait();
+        }
+    }
+
+    fn send(&self, request: Req) -> Result<OneshotReceiver<Resp>, CallError> {
+        let (reply_send, reply_recv) = oneshot();
+        let request = Request {
+            request: request,
+            reply: reply_send,
+        };
+        try!(self.mail.send(request).map_err(|_| CallError::Disconnected));
+        Ok(reply_recv)
+    }
+}
+
+struct WorkQueueShared<T> {
+    items: VecDeque<T>,
+    /// Blocked receivers, in order of arrival
+    waiters: VecDeque<(usize, Waker)>,
+    /// Receivers woken up, that did not take an item yet
+    woken: HashSet<usize>,
+    senders: usize,
+    receivers: usize,
+}
+
+impl<T> WorkQueueShared<T> {
+    /// Wake up as many waiters as there are items not promised to already
+    /// woken receivers.
+    fn dispatch(&mut self) {
+        while self.woken.len() < self.items.len() {
+            match self.waiters.pop_front() {
+                Some((id, mut waker)) => {
+                    trace!("WorkQueue: waking receiver {}", id);
+                    waker.wake(EventSet::readable());
+                    self.woken.insert(id);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn disconnect_waiters(&mut self) {
+        for (_, mut waker) in self.waiters.drain(..) {
+            waker.wake(EventSet::readable());
+        }
+    }
+}
+
+/// Create a multi-consumer work queue
+///
+/// Like `mailbox()`, but the receiving end can be cloned and used by many
+/// coroutines, on any threads. Each message is delivered to exactly one
+/// receiver. Blocked receivers are served in FIFO order, and every message
+/// wakes up only one of them.
+pub fn work_queue<T>() -> (WorkQueueSender<T>, WorkQueueReceiver<T>) {
+    let shared = Arc::new(Mutex::new(WorkQueueShared {
+        items: VecDeque::new(),
+        waiters: VecDeque::new(),
+        woken: HashSet::new(),
+        senders: 0,
+        receivers: 0,
+    }));
+
+    (WorkQueueSender::new(shared.clone()), WorkQueueReceiver::new(shared))
+}
+
+/// Work queue sending end
+///
+/// Use this inside mioco coroutines or outside of mioco itself.
+///
+/// Create with `work_queue()`
+pub struct WorkQueueSender<T> {
+    shared: Arc<Mutex<WorkQueueShared<T>>>,
+}
+
+impl<T> WorkQueueSender<T> {
+    fn new(shared: Arc<Mutex<WorkQueueShared<T>>>) -> Self {
+        shared.lock().senders += 1;
+        WorkQueueSender { shared: shared }
+    }
+
+    /// Queue `t` for one of the receivers.
+    ///
+    /// This is non-blocking operation. Fails if all receiving ends were
+    /// dropped.
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        let mut lock = self.shared.lock();
+        if lock.receivers == 0 {
+            return Err(SendError(t));
+        }
+        lock.items.push_back(t);
+        lock.dispatch();
+        Ok(())
+    }
+}
+
+impl<T> Clone for WorkQueueSender<T> {
+    fn clone(&self) -> Self {
+        WorkQueueSender::new(self.shared.clone())
+    }
+}
+
+impl<T> Drop for WorkQueueSender<T> {
+    fn drop(&mut self) {
+        let mut lock = self.shared.lock();
+        lock.senders -= 1;
+        if lock.senders == 0 {
+            lock.disconnect_waiters();
+        }
+    }
+}
+
+/// Work queue receiving end
+///
+/// Use this only inside mioco coroutines, as an asynchronous event source.
+/// Clone it to add more consumers.
+///
+/// Create with `work_queue()`
+pub struct WorkQueueReceiver<T>(RcEventSource<WorkQueueReceiverCore<T>>);
+
+struct WorkQueueReceiverCore<T> {
+    shared: Arc<Mutex<WorkQueueShared<T>>>,
+    id: usize,
+    /// Coroutine is being resumed by this receiver (see `deregister()`)
+    resuming: Cell<bool>,
+}
+
+impl<T> WorkQueueReceiver<T> {
+    fn new(shared: Arc<Mutex<WorkQueueShared<T>>>) -> Self {
+        shared.lock().receivers += 1;
+        WorkQueueReceiver(RcEventSource::new(WorkQueueReceiverCore {
+            shared: shared,
+            id: NEXT_SUBSCRIBER_ID.fetch_add(1, Ordering::Relaxed),
+            resuming: Cell::new(false),
+        }))
+    }
+}
+
+impl<T> Clone for WorkQueueReceiver<T> {
+    fn clone(&self) -> Self {
+        WorkQueueReceiver::new(self.0.io_ref().shared.clone())
+    }
+}
+
+impl<T> EventedImpl for WorkQueueReceiver<T> where T: 'static
+{
+    type Raw = WorkQueueReceiverCore<T>;
+
+    fn shared(&self) -> &RcEventSource<WorkQueueReceiverCore<T>> {
+        &self.0
+    }
+}
+
+impl<T> EventSourceTrait for WorkQueueReceiverCore<T> {
+    fn register(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        trace!("WorkQueueReceiver({}): register", token.as_usize());
+        let mut lock = self.shared.lock();
+        let id = self.id;
+
+        let mut waker = Waker::new();
+        waker.register(event_loop, token, interest);
+
+        if lock.senders == 0 {
+            waker.wake(EventSet::readable());
+            return;
+        }
+
+        if lock.woken.remove(&id) {
+            if !lock.items.is_empty() {
+                // Woken up before, but did not take the item yet
+                lock.woken.insert(id);
+                waker.wake(EventSet::readable());
+                return;
+            }
+            // Item was taken by someone else; keep the place in the line
+            lock.waiters.push_front((id, waker));
+        } else {
+            lock.waiters.push_back((id, waker));
+        }
+        lock.dispatch();
+    }
+
+    fn reregister(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        self.register(event_loop, token, interest)
+    }
+
+    fn deregister(&self, _event_loop: &mut EventLoop<Handler>, token: Token) {
+        trace!("WorkQueueReceiver({}): deregister", token.as_usize());
+        let id = self.id;
+        let mut lock = self.shared.lock();
+        lock.waiters.retain(|&(waiter_id, _)| waiter_id != id);
+        // Woken up, but resumed by another event source: the wakeup would
+        // be lost, so pass it on.
+        if !self.resuming.get() && lock.woken.remove(&id) {
+            lock.dispatch();
+        }
+        self.resuming.set(false);
+    }
+
+    fn should_resume(&self) -> bool {
+        true
+    }
+
+    fn on_resume(&self) {
+        self.resuming.set(true);
+    }
+}
+
+impl<T> Drop for WorkQueueReceiverCore<T> {
+    fn drop(&mut self) {
+        let id = self.id;
+        let mut lock = self.shared.lock();
+        lock.receivers -= 1;
+        lock.waiters.retain(|&(waiter_id, _)| waiter_id != id);
+        // Pass the wakeup on, if it was not used
+        lock.woken.remove(&id);
+        lock.dispatch();
+        if lock.receivers == 0 {
+            // Dropped after unlocking: they might hold senders of this queue
+            let items = mem::replace(&mut lock.items, VecDeque::new());
+            drop(lock);
+            drop(items);
+        }
+    }
+}
+
+impl<T> WorkQueueReceiver<T> where T: 'static
+{
+    /// Receive a message.
+    ///
+    /// Will block coroutine if no messages are available. Fails once the
+    /// queue is empty and all sending ends were dropped.
+    pub fn read(&self) -> Result<T, RecvError> {
+        loop {
+            match self.try_read() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            self.block_on(RW::read())
+        }
+    }
+
+    /// Try receiving a message.
+    ///
+    /// This will not block.
+    pub fn try_read(&self) -> Result<T, TryRecvError> {
+        let io_ref = self.0.io_ref();
+        let mut lock = io_ref.shared.lock();
+
+        match lock.items.pop_front() {
+            Some(t) => {
+                lock.woken.remove(&io_ref.id);
+                Ok(t)
+            }
+            None if lock.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+unsafe impl<T> Send for WorkQueueReceiver<T> {}
+
+/// Forward messages from a `std::sync::mpsc::Receiver` to a mailbox.
+///
+/// Makes results delivered over std channels usable in `select!`. All
+/// forwarded channels are served by one thread, waiting on all of them at
+/// once, that moves every message to the mailbox returned for its
+/// channel. The thread is started on first use, and exits when no
+/// channels are left.
+///
+/// Forwarding of `rx` ends once it is disconnected, or when a message
+/// arrives after the mailbox was dropped.
+///
+/// If you control the sending side, send to a `MailboxOuterEnd` directly
+/// instead: it works from any thread.
+pub fn forward<T>(rx: mpsc::Receiver<T>) -> MailboxInnerEnd<T>
+    where T: Send + 'static
+{
+    let (mail_send, mail_recv) = mailbox();
+    let forwarding = Box::new(Forwarding {
+        rx: rx,
+        mailbox: mail_send,
+    });
+
+    let mut forwarder = FORWARDER.lock().unwrap();
+    if forwarder.is_none() {
+        let (control_send, control_recv) = mpsc::channel();
+        thread::Builder::new()
+            .name("mioco_forward".to_owned())
+            .spawn(move || run_forwarder(control_recv))
+            .expect("Couldn't spawn forwarding thread");
+        *forwarder = Some(control_send);
+    }
+    forwarder.as_ref()
+             .unwrap()
+             .send(forwarding)
+             .expect("forwarding thread exited");
+
+    mail_recv
+}
+
+lazy_static! {
+    /// Control channel of the forwarding thread, while it runs
+    static ref FORWARDER: StdMutex<Option<mpsc::Sender<Box<Forward>>>> = StdMutex::new(None);
+}
+
+/// Channel served by the forwarding thread
+trait Forward: Send {
+    /// Add the channel to `select`.
+    fn select_add<'a>(&'a self, select: &'a mpsc::Select) -> Box<SelectHandle + 'a>;
+
+    /// Move a message to the mailbox, if there is one.
+    ///
+    /// Returns `false` when forwarding has ended.
+    fn forward(&mut self) -> bool;
+}
+
+trait SelectHandle {
+    fn id(&self) -> usize;
+}
+
+impl<'a, T: Send> SelectHandle for mpsc::Handle<'a, T> {
+    fn id(&self) -> usize {
+        mpsc::Handle::id(self)
+    }
+}
+
+struct Forwarding<T> {
+    rx: mpsc::Receiver<T>,
+    mailbox: MailboxOuterEnd<T>,
+}
+
+impl<T> Forward for Forwarding<T> where T: Send + 'static
+{
+    fn select_add<'a>(&'a self, select: &'a mpsc::Select) -> Box<SelectHandle + 'a> {
+        // Added handles must not move
+        let mut handle = Box::new(select.handle(&self.rx));
+        unsafe {
+            handle.add();
+        }
+        handle
+    }
+
+    fn forward(&mut self) -> bool {
+        match self.rx.try_recv() {
+            Ok(t) => self.mailbox.send(t).is_ok(),
+            Err(mpsc::TryRecvError::Empty) => true,
+            Err(mpsc::TryRecvError::Disconnected) => false,
+        }
+    }
+}
+
+fn run_forwarder(control: mpsc::Receiver<Box<Forward>>) {
+    let mut forwardings: Vec<Box<Forward>> = Vec::new();
+    loop {
+        while let Ok(forwarding) = control.try_recv() {
+            forwardings.push(forwarding);
+        }
+
+        if forwardings.is_empty() {
+            // No one can add a channel while `FORWARDER` is locked
+            let mut forwarder = FORWARDER.lock().unwrap();
+            match control.try_recv() {
+                Ok(forwarding) => forwardings.push(forwarding),
+                Err(_) => {
+                    trace!("forward: no channels left; exiting");
+                    *forwarder = None;
+                    return;
+                }
+            }
+        }
+
+        let ready = {
+            let select = mpsc::Select::new();
+            let mut control_handle = select.handle(&control);
+            unsafe {
+                control_handle.add();
+            }
+            let handles: Vec<_> = forwardings.iter()
+                                             .map(|f| f.select_add(&select))
+                                             .collect();
+            let id = select.wait();
+            handles.iter().position(|handle| handle.id() == id)
+        };
+
+        if let Some(i) = ready {
+            if !forwardings[i].forward() {
+                forwardings.swap_remove(i);
+            }
+        }
+    }
+}
+
+struct NotifyShared {
+    notified: bool,
+    waker: Waker,
+    parked: Option<thread::Thread>,
+}
+
+/// Create a notification pair
+///
+/// A way to wake up a coroutine (or a thread) when "something happened",
+/// from anywhere: other coroutines, threads, or callbacks of external
+/// libraries. Notifications are coalesced: any number of them before
+/// `Notified::wait()` returns wake it up once.
+pub fn notify() -> (Notifier, Notified) {
+    let shared = Arc::new(Mutex::new(NotifyShared {
+        notified: false,
+        waker: Waker::new(),
+        parked: None,
+    }));
+
+    (Notifier { shared: shared.clone() },
+     Notified(RcEventSource::new(NotifiedCore(shared))))
+}
+
+/// Notification sending end
+///
+/// Create with `notify()`
+#[derive(Clone)]
+pub struct Notifier {
+    shared: Arc<Mutex<NotifyShared>>,
+}
+
+impl Notifier {
+    /// Wake up the receiving end.
+    ///
+    /// This is non-blocking operation.
+    pub fn notify(&self) {
+        let mut lock = self.shared.lock();
+        lock.notified = true;
+        lock.waker.wake(EventSet::readable());
+        if let Some(thread) = lock.parked.take() {
+            thread.unpark();
+        }
+    }
+}
+
+/// Notification receiving end
+///
+/// Inside mioco coroutines it is an asynchronous event source, readable
+/// when notified.
+///
+/// Create with `notify()`
+pub struct Notified(RcEventSource<NotifiedCore>);
+
+struct NotifiedCore(Arc<Mutex<NotifyShared>>);
+
+impl EventedImpl for Notified {
+    type Raw = NotifiedCore;
+
+    fn shared(&self) -> &RcEventSource<NotifiedCore> {
+        &self.0
+    }
+}
+
+impl EventSourceTrait for NotifiedCore {
+    fn register(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        trace!("Notified({}): register", token.as_usize());
+        let mut lock = self.0.lock();
+        lock.waker.register(event_loop, token, interest);
+
+        if lock.notified {
+            lock.waker.wake(EventSet::readable());
+        }
+    }
+
+    fn reregister(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        self.register(event_loop, token, interest)
+    }
+
+    fn deregister(&self, _event_loop: &mut EventLoop<Handler>, token: Token) {
+        trace!("Notified({}): deregister", token.as_usize());
+        self.0.lock().waker.deregister();
+    }
+
+    fn should_resume(&self) -> bool {
+        self.0.lock().notified
+    }
+}
+
+impl Notified {
+    /// Block until notified.
+    ///
+    /// Blocks coroutine, or parks the thread outside of coroutines.
+    pub fn wait(&self) {
+        loop {
+            if self.try_wait() {
+                return;
+            }
+
+            if in_coroutine() {
+                self.block_on(RW::read());
+            } else {
+                {
+                    let io_ref = self.0.io_ref();
+                    let mut lock = io_ref.0.lock();
+                    if lock.notified {
+                        continue;
+                    }
+                    lock.parked = Some(thread::current());
+                }
+                thread::park();
+            }
+        }
+    }
+
+    /// Check and clear notification.
+    ///
+    /// This will not block.
+    pub fn try_wait(&self) -> bool {
+        let io_ref = self.0.io_ref();
+        let mut lock = io_ref.0.lock();
+        let notified = lock.notified;
+        lock.notified = false;
+        notified
+    }
+}
+
+unsafe impl Send for Notified {}
+
+/// Message waiting in a priority mailbox
+///
+/// Ordered by priority, then by arrival (earlier first).
+struct PriorityEntry<T, P> {
+    priority: P,
+    seq: u64,
+    t: T,
+}
+
+impl<T, P: Ord> PartialEq for PriorityEntry<T, P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl<T, P: Ord> Eq for PriorityEntry<T, P> {}
+
+impl<T, P: Ord> PartialOrd for PriorityEntry<T, P> {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T, P: Ord> Ord for PriorityEntry<T, P> {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        match self.priority.cmp(&other.priority) {
+            CmpOrdering::Equal => other.seq.cmp(&self.seq),
+            ordering => ordering,
+        }
+    }
+}
+
+struct PriorityShared<T, P: Ord> {
+    inn: BinaryHeap<PriorityEntry<T, P>>,
+    /// Sequence number of the next message
+    next_seq: u64,
+    waker: Waker,
+    /// Number of sending ends alive
+    senders: usize,
+    /// Receiving end is alive
+    receiver_alive: bool,
+}
+
+impl<T, P: Ord> PriorityShared<T, P> {
+    /// Is the receiving end ready: has a message or will never get one
+    fn is_readable(&self) -> bool {
+        !self.inn.is_empty() || self.senders == 0
+    }
+}
+
+/// Create a priority Mailbox
+///
+/// Like `mailbox()`, but every message is sent with a priority, and the
+/// receiving end always gets the highest-priority message first. Messages
+/// of equal priority are received in order they were sent.
+pub fn priority_mailbox<T, P: Ord>() -> (PriorityMailboxOuterEnd<T, P>,
+                                         PriorityMailboxInnerEnd<T, P>) {
+    let shared = Arc::new(Mutex::new(PriorityShared {
+        inn: BinaryHeap::new(),
+        next_seq: 0,
+        waker: Waker::new(),
+        senders: 1,
+        receiver_alive: true,
+    }));
+
+    (PriorityMailboxOuterEnd { shared: shared.clone() },
+     PriorityMailboxInnerEnd(RcEventSource::new(PriorityMailboxInnerCore(shared))))
+}
+
+/// Priority mailbox sending end
+///
+/// Use this inside mioco coroutines or outside of mioco itself.
+///
+/// Create with `priority_mailbox()`
+pub struct PriorityMailboxOuterEnd<T, P: Ord> {
+    shared: Arc<Mutex<PriorityShared<T, P>>>,
+}
+
+impl<T, P: Ord> PriorityMailboxOuterEnd<T, P> {
+    /// Deliver `t` with `priority` to the other end of the mailbox.
+    ///
+    /// This is non-blocking operation. Fails if the receiving end was
+    /// dropped.
+    pub fn send(&self, priority: P, t: T) -> Result<(), SendError<T>> {
+        let mut lock = self.shared.lock();
+        if !lock.receiver_alive {
+            return Err(SendError(t));
+        }
+        let seq = lock.next_seq;
+        lock.next_seq += 1;
+        lock.inn.push(PriorityEntry {
+            priority: priority,
+            seq: seq,
+            t: t,
+        });
+        lock.waker.wake(EventSet::readable());
+        Ok(())
+    }
+}
+
+impl<T, P: Ord> Clone for PriorityMailboxOuterEnd<T, P> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        PriorityMailboxOuterEnd { shared: self.shared.clone() }
+    }
+}
+
+impl<T, P: Ord> Drop for PriorityMailboxOuterEnd<T, P> {
+    fn drop(&mut self) {
+        let mut lock = self.shared.lock();
+        lock.senders -= 1;
+        if lock.senders == 0 {
+            lock.waker.wake(EventSet::readable());
+        }
+    }
+}
+
+/// Priority mailbox receiving end
+///
+/// Use this only inside mioco coroutines, as an asynchronous event source.
+///
+/// Create with `priority_mailbox()`
+pub struct PriorityMailboxInnerEnd<T, P: Ord>(RcEventSource<PriorityMailboxInnerCore<T, P>>);
+
+struct PriorityMailboxInnerCore<T, P: Ord>(Arc<Mutex<PriorityShared<T, P>>>);
+
+impl<T, P> EventedImpl for PriorityMailboxInnerEnd<T, P>
+    where T: 'static,
+          P: Ord + 'static
+{
+    type Raw = PriorityMailboxInnerCore<T, P>;
+
+    fn shared(&self) -> &RcEventSource<PriorityMailboxInnerCore<T, P>> {
+        &self.0
+    }
+}
+
+impl<T, P: Ord> EventSourceTrait for PriorityMailboxInnerCore<T, P> {
+    fn register(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        trace!("PriorityMailboxInnerEnd({}): register", token.as_usize());
+        let mut lock = self.0.lock();
+        lock.waker.register(event_loop, token, interest);
+
+        if lock.is_readable() {
+            trace!("PriorityMailboxInnerEnd({}): ready; self notify",
+                   token.as_usize());
+            lock.waker.wake(EventSet::readable());
+        }
+    }
+
+    fn reregister(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        self.register(event_loop, token, interest)
+    }
+
+    fn deregister(&self, _event_loop: &mut EventLoop<Handler>, token: Token) {
+        trace!("PriorityMailboxInnerEnd({}): deregister", token.as_usize());
+        self.0.lock().waker.deregister();
+    }
+
+    fn should_resume(&self) -> bool {
+        self.0.lock().is_readable()
+    }
+}
+
+impl<T, P: Ord> Drop for PriorityMailboxInnerCore<T, P> {
+    fn drop(&mut self) {
+        let mut lock = self.0.lock();
+        lock.receiver_alive = false;
+        // Dropped after unlocking: they might hold senders of this mailbox
+        let inn = mem::replace(&mut lock.inn, BinaryHeap::new());
+        drop(lock);
+        drop(inn);
+    }
+}
+
+impl<T, P> PriorityMailboxInnerEnd<T, P>
+    where T: 'static,
+          P: Ord + 'static
+{
+    /// Receive the highest-priority message.
+    ///
+    /// Will block coroutine if no messages are available. Fails once the
+    /// mailbox is empty and all sending ends were dropped.
+    pub fn read(&self) -> Result<T, RecvError> {
+        loop {
+            match self.try_read() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            self.block_on(RW::read())
+        }
+    }
+
+    /// Try receiving the highest-priority message.
+    ///
+    /// This will not block.
+    pub fn try_read(&self) -> Result<T, TryRecvError> {
+        let io_ref = self.0.io_ref();
+        let mut lock = io_ref.0.lock();
+
+        match lock.inn.pop() {
+            Some(entry) => Ok(entry.t),
+            None if lock.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+unsafe impl<T, P: Ord> Send for PriorityMailboxInnerEnd<T, P> {}
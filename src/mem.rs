@@ -0,0 +1,35 @@
+use super::evented::{EventedImpl, MioAdapter};
+use super::sim::{self, SimStream};
+use super::tcp::Shutdown;
+
+use std::io;
+
+/// In-memory byte stream
+///
+/// One end of a pair created with `duplex()`. Behaves like a connected
+/// `TcpStream`: reads block until the other end writes, writes block while
+/// the other end's buffer is full, and reads return `0` once the other end
+/// is dropped or shut down for writing.
+///
+/// Being a `MioAdapter`, it is subject to fault injection (see
+/// `Config::set_faults()`).
+///
+/// Use it only inside mioco coroutines.
+pub type MemStream = MioAdapter<SimStream>;
+
+/// Create a pair of connected in-memory streams.
+///
+/// Each direction buffers at most `capacity` bytes.
+pub fn duplex(capacity: usize) -> (MemStream, MemStream) {
+    assert!(capacity > 0);
+
+    let (a, b) = sim::duplex(capacity);
+    (MioAdapter::new(a), MioAdapter::new(b))
+}
+
+impl MemStream {
+    /// Shutdown the stream.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.shared().io_ref().shutdown(how)
+    }
+}
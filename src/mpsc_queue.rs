@@ -0,0 +1,105 @@
+// Lock-free multi-producer, single-consumer queue
+//
+// Non-intrusive variant of Dmitry Vyukov's MPSC queue
+// (http://www.1024cores.net/home/lock-free-algorithms/queues/non-intrusive-mpsc-node-based-queue).
+// Pushing is wait-free: a single atomic swap. The price is that a
+// producer preempted between swapping `head` and linking the previous
+// node leaves the queue briefly "inconsistent": the consumer can not see
+// that message, nor any pushed after it, until the producer continues.
+
+use std::cell::UnsafeCell;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+struct Node<T> {
+    next: AtomicPtr<Node<T>>,
+    value: Option<T>,
+}
+
+impl<T> Node<T> {
+    fn new(value: Option<T>) -> *mut Node<T> {
+        Box::into_raw(Box::new(Node {
+            next: AtomicPtr::new(ptr::null_mut()),
+            value: value,
+        }))
+    }
+}
+
+pub enum PopResult<T> {
+    Data(T),
+    Empty,
+    /// A push is in progress
+    Inconsistent,
+}
+
+pub struct Queue<T> {
+    /// Most recently pushed node
+    head: AtomicPtr<Node<T>>,
+    /// Stub node; its successor holds the oldest message
+    tail: UnsafeCell<*mut Node<T>>,
+}
+
+unsafe impl<T: Send> Send for Queue<T> {}
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T> Queue<T> {
+    pub fn new() -> Self {
+        let stub = Node::new(None);
+        Queue {
+            head: AtomicPtr::new(stub),
+            tail: UnsafeCell::new(stub),
+        }
+    }
+
+    /// Push a value. Can be called from any number of threads.
+    pub fn push(&self, t: T) {
+        let node = Node::new(Some(t));
+        let prev = self.head.swap(node, Ordering::AcqRel);
+        unsafe {
+            (*prev).next.store(node, Ordering::Release);
+        }
+    }
+
+    /// Is there a value `pop()` would return?
+    ///
+    /// Unsafe: only the consumer can call it.
+    pub unsafe fn has_data(&self) -> bool {
+        let tail = *self.tail.get();
+        !(*tail).next.load(Ordering::Acquire).is_null()
+    }
+
+    /// Pop the oldest value.
+    ///
+    /// Unsafe: there must be only one consumer at a time.
+    pub unsafe fn pop(&self) -> PopResult<T> {
+        let tail = *self.tail.get();
+        let next = (*tail).next.load(Ordering::Acquire);
+
+        if !next.is_null() {
+            *self.tail.get() = next;
+            debug_assert!((*tail).value.is_none());
+            let t = (*next).value.take().expect("mpsc_queue: node without value");
+            drop(Box::from_raw(tail));
+            return PopResult::Data(t);
+        }
+
+        if self.head.load(Ordering::Acquire) == tail {
+            PopResult::Empty
+        } else {
+            PopResult::Inconsistent
+        }
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        unsafe {
+            let mut node = *self.tail.get();
+            while !node.is_null() {
+                let next = (*node).next.load(Ordering::Relaxed);
+                drop(Box::from_raw(node));
+                node = next;
+            }
+        }
+    }
+}
@@ -0,0 +1,392 @@
+// Reporting of coroutine stack overflows
+//
+// Coroutine stacks end with a protection page, so an overflow is a plain
+// SIGSEGV. The handler installed here runs on an alternate signal stack
+// (the faulting stack is exhausted), checks if the faulting address is
+// in the protection page of the coroutine running on this thread, and
+// if so prints which coroutine overflowed before aborting. Any other fault
+// is passed on to the previously installed handler.
+//
+// The handler only reads plain thread-local atomics (see `Current`),
+// which `coroutine::jump_in` updates on every switch, and formats its
+// report with `SignalBuf`, which neither allocates nor locks.
+//
+// Kernel structures are declared by hand (the `libc` version in use does
+// not have them), so this is enabled only for architectures whose layouts
+// and signal numbers were checked.
+
+#[cfg(all(target_os = "linux",
+          any(target_arch = "x86", target_arch = "x86_64",
+              target_arch = "arm", target_arch = "aarch64")))]
+pub use self::imp::{init, page_size, swap_current, alt_stack_enabled, AltStack};
+
+#[cfg(not(all(target_os = "linux",
+              any(target_arch = "x86", target_arch = "x86_64",
+                  target_arch = "arm", target_arch = "aarch64"))))]
+pub use self::noop::{init, page_size, swap_current, alt_stack_enabled, AltStack};
+
+/// What the signal handler knows about the running coroutine
+#[derive(Clone, Copy)]
+pub struct Current {
+    /// Protection page range: `[guard_start, guard_end)`
+    pub guard_start: usize,
+    pub guard_end: usize,
+    pub uid: usize,
+    pub stack_size: usize,
+    /// Coroutine name (`name_len == 0` if none); must stay valid while
+    /// installed
+    pub name_ptr: usize,
+    pub name_len: usize,
+}
+
+impl Current {
+    pub fn none() -> Self {
+        Current {
+            guard_start: 0,
+            guard_end: 0,
+            uid: 0,
+            stack_size: 0,
+            name_ptr: 0,
+            name_len: 0,
+        }
+    }
+}
+
+/// Protection page range of a stack starting at `bottom`
+pub fn guard_range(bottom: usize) -> (usize, usize) {
+    (bottom, bottom + page_size())
+}
+
+/// Is `addr` within `[guard_start, guard_end)`
+///
+/// Always false for an empty range, meaning no coroutine is running.
+pub fn in_guard(addr: usize, guard_start: usize, guard_end: usize) -> bool {
+    guard_start != 0 && addr >= guard_start && addr < guard_end
+}
+
+/// Fixed-size message that can be composed inside a signal handler
+///
+/// Unlike `write!`, it never allocates, locks or panics; whatever does not
+/// fit is dropped.
+pub struct SignalBuf {
+    buf: [u8; 512],
+    len: usize,
+}
+
+impl SignalBuf {
+    pub fn new() -> Self {
+        SignalBuf {
+            buf: [0; 512],
+            len: 0,
+        }
+    }
+
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            if self.len == self.buf.len() {
+                return;
+            }
+            self.buf[self.len] = b;
+            self.len += 1;
+        }
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        self.push_bytes(s.as_bytes());
+    }
+
+    /// Append `n` in decimal.
+    pub fn push_usize(&mut self, mut n: usize) {
+        let mut digits = [0u8; 20];
+        let mut i = digits.len();
+        loop {
+            i -= 1;
+            digits[i] = b'0' + (n % 10) as u8;
+            n /= 10;
+            if n == 0 {
+                break;
+            }
+        }
+        self.push_bytes(&digits[i..]);
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Report printed when a coroutine overflows its stack
+pub fn overflow_message(uid: usize, name: Option<&str>, stack_size: usize) -> SignalBuf {
+    let mut msg = SignalBuf::new();
+    msg.push_str("mioco: coroutine ");
+    msg.push_usize(uid);
+    if let Some(name) = name {
+        msg.push_str(" (");
+        msg.push_str(name);
+        msg.push_str(")");
+    }
+    msg.push_str(" overflowed its ");
+    msg.push_usize(stack_size / 1024);
+    msg.push_str(" KiB stack\n");
+    msg
+}
+
+#[cfg(all(target_os = "linux",
+          any(target_arch = "x86", target_arch = "x86_64",
+              target_arch = "arm", target_arch = "aarch64")))]
+mod imp {
+    use super::{Current, in_guard, overflow_message};
+    use libc::{self, c_int, c_void, size_t};
+
+    use std::mem;
+    use std::ptr;
+    use std::slice;
+    use std::str;
+    use std::sync::{Once, ONCE_INIT};
+    use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+    const SA_SIGINFO: c_int = 0x00000004;
+    const SA_ONSTACK: c_int = 0x08000000;
+    const SS_DISABLE: c_int = 2;
+    const SIGBUS: c_int = 7;
+
+    const SIG_DFL: usize = 0;
+    const SIG_IGN: usize = 1;
+
+    const ALT_STACK_SIZE: usize = 64 * 1024;
+
+    #[repr(C)]
+    struct SigAction {
+        sa_sigaction: usize,
+        sa_mask: [u64; 16],
+        sa_flags: c_int,
+        sa_restorer: usize,
+    }
+
+    #[repr(C)]
+    struct SigInfo {
+        si_signo: c_int,
+        si_errno: c_int,
+        si_code: c_int,
+        si_addr: *mut c_void,
+    }
+
+    #[repr(C)]
+    struct SigStack {
+        ss_sp: *mut c_void,
+        ss_flags: c_int,
+        ss_size: size_t,
+    }
+
+    extern "C" {
+        fn sigaction(signum: c_int, act: *const SigAction, oldact: *mut SigAction) -> c_int;
+        fn sigaltstack(ss: *const SigStack, oss: *mut SigStack) -> c_int;
+    }
+
+    static INIT: Once = ONCE_INIT;
+    static mut PREV_SEGV: SigAction = SigAction {
+        sa_sigaction: 0,
+        sa_mask: [0; 16],
+        sa_flags: 0,
+        sa_restorer: 0,
+    };
+    static mut PREV_BUS: SigAction = SigAction {
+        sa_sigaction: 0,
+        sa_mask: [0; 16],
+        sa_flags: 0,
+        sa_restorer: 0,
+    };
+
+    static PAGE_SIZE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    #[thread_local]
+    static GUARD_START: AtomicUsize = ATOMIC_USIZE_INIT;
+    #[thread_local]
+    static GUARD_END: AtomicUsize = ATOMIC_USIZE_INIT;
+    #[thread_local]
+    static UID: AtomicUsize = ATOMIC_USIZE_INIT;
+    #[thread_local]
+    static STACK_SIZE: AtomicUsize = ATOMIC_USIZE_INIT;
+    #[thread_local]
+    static NAME_PTR: AtomicUsize = ATOMIC_USIZE_INIT;
+    #[thread_local]
+    static NAME_LEN: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// Install the process-wide SIGSEGV/SIGBUS handler (once).
+    pub fn init() {
+        INIT.call_once(|| unsafe {
+            let mut action: SigAction = mem::zeroed();
+            action.sa_sigaction = handler as usize;
+            action.sa_flags = SA_SIGINFO | SA_ONSTACK;
+            sigaction(libc::SIGSEGV, &action, &mut PREV_SEGV);
+            sigaction(SIGBUS, &action, &mut PREV_BUS);
+        });
+    }
+
+    /// Size of the protection page of coroutine stacks
+    pub fn page_size() -> usize {
+        let size = PAGE_SIZE.load(Ordering::Relaxed);
+        if size != 0 {
+            return size;
+        }
+        let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        PAGE_SIZE.store(size, Ordering::Relaxed);
+        size
+    }
+
+    /// Set what the handler reports for this thread, returning the
+    /// previous value.
+    pub fn swap_current(current: Current) -> Current {
+        // Clear the guard range first, so the handler never pairs it
+        // with another coroutine's details.
+        let prev = Current {
+            guard_start: GUARD_START.swap(0, Ordering::SeqCst),
+            guard_end: GUARD_END.swap(0, Ordering::SeqCst),
+            uid: UID.load(Ordering::SeqCst),
+            stack_size: STACK_SIZE.load(Ordering::SeqCst),
+            name_ptr: NAME_PTR.load(Ordering::SeqCst),
+            name_len: NAME_LEN.load(Ordering::SeqCst),
+        };
+        UID.store(current.uid, Ordering::SeqCst);
+        STACK_SIZE.store(current.stack_size, Ordering::SeqCst);
+        NAME_PTR.store(current.name_ptr, Ordering::SeqCst);
+        NAME_LEN.store(current.name_len, Ordering::SeqCst);
+        GUARD_END.store(current.guard_end, Ordering::SeqCst);
+        GUARD_START.store(current.guard_start, Ordering::SeqCst);
+        prev
+    }
+
+    extern "C" fn handler(signum: c_int, info: *mut SigInfo, ctx: *mut c_void) {
+        let addr = unsafe { (*info).si_addr as usize };
+
+        let guard_start = GUARD_START.load(Ordering::SeqCst);
+        let guard_end = GUARD_END.load(Ordering::SeqCst);
+        if in_guard(addr, guard_start, guard_end) {
+            let uid = UID.load(Ordering::SeqCst);
+            let stack_size = STACK_SIZE.load(Ordering::SeqCst);
+            let name_ptr = NAME_PTR.load(Ordering::SeqCst);
+            let name_len = NAME_LEN.load(Ordering::SeqCst);
+            let name = if name_len == 0 {
+                None
+            } else {
+                let bytes = unsafe { slice::from_raw_parts(name_ptr as *const u8, name_len) };
+                str::from_utf8(bytes).ok()
+            };
+
+            let msg = overflow_message(uid, name, stack_size);
+            let msg = msg.as_bytes();
+            unsafe {
+                libc::write(2, msg.as_ptr() as *const c_void, msg.len() as size_t);
+                libc::abort();
+            }
+        }
+
+        // Not ours: chain to the previously installed handler.
+        unsafe {
+            let prev = if signum == SIGBUS {
+                &PREV_BUS
+            } else {
+                &PREV_SEGV
+            };
+            match prev.sa_sigaction {
+                SIG_DFL | SIG_IGN => {
+                    // Restore it and return, so the fault happens again
+                    // and is handled the way it would be without mioco.
+                    sigaction(signum, prev, ptr::null_mut());
+                }
+                f if prev.sa_flags & SA_SIGINFO != 0 => {
+                    let f: extern "C" fn(c_int, *mut SigInfo, *mut c_void) = mem::transmute(f);
+                    f(signum, info, ctx);
+                }
+                f => {
+                    let f: extern "C" fn(c_int) = mem::transmute(f);
+                    f(signum);
+                }
+            }
+        }
+    }
+
+    /// Does this thread have an alternate signal stack
+    pub fn alt_stack_enabled() -> bool {
+        unsafe {
+            let mut old: SigStack = mem::zeroed();
+            sigaltstack(ptr::null(), &mut old);
+            old.ss_flags & SS_DISABLE == 0
+        }
+    }
+
+    /// Alternate signal stack of a mioco thread
+    ///
+    /// Installed only if the thread does not have one already. Disabled
+    /// and freed on drop.
+    pub struct AltStack {
+        stack: Option<Vec<u8>>,
+    }
+
+    impl AltStack {
+        pub fn new() -> Self {
+            if alt_stack_enabled() {
+                return AltStack { stack: None };
+            }
+
+            unsafe {
+                let mut stack = vec![0u8; ALT_STACK_SIZE];
+                let new = SigStack {
+                    ss_sp: stack.as_mut_ptr() as *mut c_void,
+                    ss_flags: 0,
+                    ss_size: ALT_STACK_SIZE as size_t,
+                };
+                sigaltstack(&new, ptr::null_mut());
+                AltStack { stack: Some(stack) }
+            }
+        }
+    }
+
+    impl Drop for AltStack {
+        fn drop(&mut self) {
+            if self.stack.is_some() {
+                let disable = SigStack {
+                    ss_sp: ptr::null_mut(),
+                    ss_flags: SS_DISABLE,
+                    ss_size: 0,
+                };
+                unsafe { sigaltstack(&disable, ptr::null_mut()) };
+            }
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux",
+              any(target_arch = "x86", target_arch = "x86_64",
+                  target_arch = "arm", target_arch = "aarch64"))))]
+mod noop {
+    use super::Current;
+
+    pub fn init() {}
+
+    #[cfg(unix)]
+    pub fn page_size() -> usize {
+        unsafe { ::libc::sysconf(::libc::_SC_PAGESIZE) as usize }
+    }
+
+    #[cfg(not(unix))]
+    pub fn page_size() -> usize {
+        4096
+    }
+
+    pub fn swap_current(_current: Current) -> Current {
+        Current::none()
+    }
+
+    pub fn alt_stack_enabled() -> bool {
+        false
+    }
+
+    pub struct AltStack;
+
+    impl AltStack {
+        pub fn new() -> Self {
+            AltStack
+        }
+    }
+}
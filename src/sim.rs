@@ -0,0 +1,1066 @@
+//! Simulated, in-memory network
+//!
+//! `SimNetwork` connects any number of simulated hosts, identified by IP
+//! addresses, within one process. Links between hosts have configurable
+//! latency, bandwidth, packet loss and reordering, and can be partitioned
+//! at any time.
+//!
+//! There is no separate socket API: with the `sim` cargo feature enabled,
+//! `tcp::TcpListener`, `tcp::TcpStream`, `udp::UdpSocket`,
+//! `unix::UnixListener` and `unix::UnixStream` created by a coroutine
+//! placed on a simulated host (see `set_current_host()` and
+//! `Config::set_sim_host()`) are simulated, without touching real ports.
+//! Without the feature, these types are plain `mio` sockets, and
+//! coroutines can't be placed on simulated hosts.
+//! Everything else about them stays the same: they block the coroutine
+//! using them, can be used in `select!`, and are subject to fault
+//! injection. Combined with `mioco::test::Runtime` (seeded scheduling and
+//! a virtual clock), tests of distributed protocols are fast and
+//! reproducible, and run the same code as production.
+//!
+//! Semantics:
+//!
+//! * streams are reliable and ordered; a lost segment is retransmitted,
+//!   which delays it (and everything after it) by a round-trip time;
+//! * datagrams can be lost, or reordered by delaying them by an extra
+//!   link latency;
+//! * each direction of a link between two hosts has its own bandwidth;
+//! * streams have a bounded buffer (see `SimNetwork::set_buffer_size()`),
+//!   so writers block until the peer reads;
+//! * partitioning two hosts resets streams between them, makes new
+//!   connections fail with `TimedOut` and drops datagrams;
+//! * sockets can only bind to the address of their host (or the
+//!   unspecified address, meaning the same);
+//! * unix streams are local to a host, and not affected by link settings;
+//! * options like `set_nodelay()` are accepted and ignored; cloning,
+//!   multicast and passing file descriptors are not supported.
+
+#[cfg(feature = "sim")]
+use super::{tl_coroutine_current, in_coroutine};
+use super::evented::EventSourceTrait;
+use super::mio_orig::{self, EventLoop, Token, EventSet};
+use super::rng::XorShift;
+use super::tcp::Shutdown;
+use super::thread::Handler;
+use super::timer::Clock;
+use super::wake::Waker;
+
+use spin::Mutex;
+use time::{SteadyTime, Duration};
+
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+
+/// Default size of each direction of a stream buffer
+const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// First port used for ephemeral ports
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Properties of a simulated link
+#[derive(Copy, Clone, Debug)]
+pub struct Link {
+    latency_ms: i64,
+    bandwidth: Option<u64>,
+    loss: f64,
+    reorder: f64,
+}
+
+impl Link {
+    /// Create a perfect link: no latency, unlimited bandwidth, no loss and
+    /// no reordering.
+    pub fn new() -> Self {
+        Link {
+            latency_ms: 0,
+            bandwidth: None,
+            loss: 0.0,
+            reorder: 0.0,
+        }
+    }
+
+    /// Set one-way latency.
+    pub fn set_latency(&mut self, latency_ms: i64) -> &mut Self {
+        self.latency_ms = latency_ms;
+        self
+    }
+
+    /// Set bandwidth in bytes per second (`None` for unlimited).
+    pub fn set_bandwidth(&mut self, bytes_per_sec: Option<u64>) -> &mut Self {
+        self.bandwidth = bytes_per_sec;
+        self
+    }
+
+    /// Set probability of losing a packet (`0.0 - 1.0`).
+    pub fn set_loss(&mut self, probability: f64) -> &mut Self {
+        self.loss = probability;
+        self
+    }
+
+    /// Set probability of delaying a datagram past the ones sent after it
+    /// (`0.0 - 1.0`).
+    pub fn set_reorder(&mut self, probability: f64) -> &mut Self {
+        self.reorder = probability;
+        self
+    }
+}
+
+/// Simulated network
+///
+/// `SimNetwork` is a cheaply cloneable handle. Decisions about loss and
+/// reordering are made by a PRNG seeded with `seed`.
+#[derive(Clone)]
+pub struct SimNetwork {
+    shared: Arc<Mutex<NetShared>>,
+}
+
+struct NetShared {
+    rng: XorShift,
+    default_link: Link,
+    links: HashMap<(IpAddr, IpAddr), Link>,
+    partitions: HashSet<(IpAddr, IpAddr)>,
+    /// Time each direction of a link is busy transmitting until
+    busy_until: HashMap<(IpAddr, IpAddr), SteadyTime>,
+    buffer_size: usize,
+    next_port: u16,
+    listeners: HashMap<ListenerAddr, Arc<Mutex<ListenerShared>>>,
+    udp: HashMap<SocketAddr, Arc<Mutex<UdpShared>>>,
+    conns: Vec<Weak<Mutex<Conn>>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum ListenerAddr {
+    Tcp(SocketAddr),
+    Unix(IpAddr, PathBuf),
+}
+
+impl SimNetwork {
+    /// Create a network with all links perfect.
+    pub fn new(seed: u64) -> Self {
+        SimNetwork {
+            shared: Arc::new(Mutex::new(NetShared {
+                rng: XorShift::new(seed),
+                default_link: Link::new(),
+                links: HashMap::new(),
+                partitions: HashSet::new(),
+                busy_until: HashMap::new(),
+                buffer_size: DEFAULT_BUFFER_SIZE,
+                next_port: EPHEMERAL_PORT_START,
+                listeners: HashMap::new(),
+                udp: HashMap::new(),
+                conns: Vec::new(),
+            })),
+        }
+    }
+
+    /// Get a handle to a host with a given address.
+    ///
+    /// Hosts don't need to be created in advance: any address can be used.
+    pub fn host(&self, ip: IpAddr) -> SimHost {
+        SimHost {
+            net: self.clone(),
+            ip: ip,
+        }
+    }
+
+    /// Set properties of links without explicit settings.
+    pub fn set_default_link(&self, link: Link) -> &Self {
+        self.shared.lock().default_link = link;
+        self
+    }
+
+    /// Set properties of a link between hosts `a` and `b` (both ways).
+    pub fn set_link(&self, a: IpAddr, b: IpAddr, link: Link) -> &Self {
+        let mut lock = self.shared.lock();
+        lock.links.insert((a, b), link);
+        lock.links.insert((b, a), link);
+        self
+    }
+
+    /// Set size of each direction of buffers of streams connected from now on.
+    pub fn set_buffer_size(&self, bytes: usize) -> &Self {
+        self.shared.lock().buffer_size = bytes;
+        self
+    }
+
+    /// Cut the link between hosts `a` and `b`.
+    ///
+    /// Streams between them are reset.
+    pub fn partition(&self, a: IpAddr, b: IpAddr) {
+        let mut lock = self.shared.lock();
+        lock.partitions.insert((a, b));
+        lock.partitions.insert((b, a));
+
+        lock.conns.retain(|conn| conn.upgrade().is_some());
+        for conn in lock.conns.iter().filter_map(|conn| conn.upgrade()) {
+            let mut conn = conn.lock();
+            let between = match conn.hosts {
+                Some(hosts) => (hosts[0] == a && hosts[1] == b) || (hosts[0] == b && hosts[1] == a),
+                None => false,
+            };
+            if between {
+                conn.reset();
+            }
+        }
+    }
+
+    /// Restore the link between hosts `a` and `b`.
+    pub fn heal(&self, a: IpAddr, b: IpAddr) {
+        let mut lock = self.shared.lock();
+        lock.partitions.remove(&(a, b));
+        lock.partitions.remove(&(b, a));
+    }
+
+    /// Restore all links.
+    pub fn heal_all(&self) {
+        self.shared.lock().partitions.clear();
+    }
+}
+
+/// A packet sent over a link
+struct Transmission {
+    deliver_at: SteadyTime,
+    lost: bool,
+    reordered: bool,
+    link: Link,
+}
+
+impl NetShared {
+    fn is_partitioned(&self, src: IpAddr, dst: IpAddr) -> bool {
+        self.partitions.contains(&(src, dst))
+    }
+
+    /// Send `len` bytes from `src` to `dst` at `now`.
+    ///
+    /// Returns `None` if the hosts are partitioned.
+    fn transmit(&mut self, src: IpAddr, dst: IpAddr, len: usize, now: SteadyTime) -> Option<Transmission> {
+        if src == dst {
+            return Some(Transmission {
+                deliver_at: now,
+                lost: false,
+                reordered: false,
+                link: Link::new(),
+            });
+        }
+
+        if self.is_partitioned(src, dst) {
+            return None;
+        }
+
+        let link = *self.links.get(&(src, dst)).unwrap_or(&self.default_link);
+
+        let mut sent_at = now;
+        if let Some(bandwidth) = link.bandwidth {
+            let busy_until = self.busy_until.entry((src, dst)).or_insert(now);
+            if *busy_until > sent_at {
+                sent_at = *busy_until;
+            }
+            let tx_us = (len as u64 * 1_000_000) / cmp::max(bandwidth, 1);
+            sent_at = sent_at + Duration::microseconds(tx_us as i64);
+            *busy_until = sent_at;
+        }
+
+        Some(Transmission {
+            deliver_at: sent_at + Duration::milliseconds(link.latency_ms),
+            lost: self.rng.chance(link.loss),
+            reordered: self.rng.chance(link.reorder),
+            link: link,
+        })
+    }
+
+    fn ephemeral_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = if port == u16::max_value() {
+            EPHEMERAL_PORT_START
+        } else {
+            port + 1
+        };
+        port
+    }
+
+    fn bind_port(&mut self, ip: IpAddr, port: u16, is_free: &Fn(&NetShared, SocketAddr) -> bool) -> io::Result<SocketAddr> {
+        if port != 0 {
+            let addr = SocketAddr::new(ip, port);
+            return if is_free(self, addr) {
+                Ok(addr)
+            } else {
+                Err(io::Error::new(io::ErrorKind::AddrInUse, "address already in use"))
+            };
+        }
+
+        for _ in 0..(u16::max_value() - EPHEMERAL_PORT_START) {
+            let addr = SocketAddr::new(ip, self.ephemeral_port());
+            if is_free(self, addr) {
+                return Ok(addr);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::AddrInUse, "no free ephemeral ports"))
+    }
+}
+
+/// A host in a `SimNetwork`
+///
+/// Place coroutines on it with `set_current_host()` or
+/// `Config::set_sim_host()`.
+#[derive(Clone)]
+pub struct SimHost {
+    net: SimNetwork,
+    ip: IpAddr,
+}
+
+/// Place the current coroutine on a simulated host.
+///
+/// Sockets it creates from now on are simulated sockets of `host`, or
+/// real ones with `None`. Coroutines it spawns afterwards start on the
+/// same host.
+#[cfg(feature = "sim")]
+pub fn set_current_host(host: Option<SimHost>) {
+    tl_coroutine_current().sim_host = host;
+}
+
+/// Simulated host the current coroutine is placed on, if any.
+#[cfg(feature = "sim")]
+pub fn current_host() -> Option<SimHost> {
+    if in_coroutine() {
+        tl_coroutine_current().sim_host.clone()
+    } else {
+        None
+    }
+}
+
+fn is_unspecified(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ref ip) => ip.octets() == [0; 4],
+        IpAddr::V6(ref ip) => ip.segments() == [0; 8],
+    }
+}
+
+impl SimHost {
+    /// Address of the host.
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// Check that a socket of this host can bind to `ip`.
+    fn local_ip(&self, ip: IpAddr) -> io::Result<IpAddr> {
+        if is_unspecified(&ip) || ip == self.ip {
+            Ok(self.ip)
+        } else {
+            Err(io::Error::new(io::ErrorKind::AddrNotAvailable,
+                               "address not available on simulated host"))
+        }
+    }
+
+    /// Start listening for stream connections at `addr` (port `0` picks
+    /// a free one).
+    #[doc(hidden)]
+    pub fn tcp_listen(&self, addr: &SocketAddr) -> io::Result<SimListener> {
+        let ip = try!(self.local_ip(addr.ip()));
+        let mut net = self.net.shared.lock();
+        let addr = try!(net.bind_port(ip, addr.port(), &|net: &NetShared, addr: SocketAddr| {
+            !net.listeners.contains_key(&ListenerAddr::Tcp(addr))
+        }));
+        Ok(SimListener::new(&mut net, self.net.clone(), ListenerAddr::Tcp(addr)))
+    }
+
+    /// Connect a stream to a listener at `addr`.
+    #[doc(hidden)]
+    pub fn tcp_connect(&self, addr: &SocketAddr) -> io::Result<SimStream> {
+        let mut net = self.net.shared.lock();
+        if self.ip != addr.ip() && net.is_partitioned(self.ip, addr.ip()) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "host unreachable"));
+        }
+
+        let listener = match net.listeners.get(&ListenerAddr::Tcp(*addr)) {
+            Some(listener) => listener.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused")),
+        };
+
+        let local = SocketAddr::new(self.ip, net.ephemeral_port());
+        let conn = Conn::new(Some([self.ip, addr.ip()]), net.buffer_size);
+        net.conns.push(Arc::downgrade(&conn));
+
+        listener.lock().push(conn.clone(), Some(*addr), Some(local));
+        Ok(SimStream::new(Some(self.net.clone()), conn, 0, Some(local), Some(*addr)))
+    }
+
+    /// Create an unbound datagram socket.
+    #[doc(hidden)]
+    pub fn udp_socket(&self) -> SimUdpSocket {
+        SimUdpSocket {
+            host: self.clone(),
+            shared: Arc::new(Mutex::new(UdpShared {
+                queue: Vec::new(),
+                waker: Waker::new(),
+            })),
+            addr: None,
+            clock: Clock::current(),
+        }
+    }
+
+    /// Start listening for unix stream connections at `path`.
+    #[doc(hidden)]
+    pub fn unix_listen(&self, path: &Path) -> io::Result<SimListener> {
+        let mut net = self.net.shared.lock();
+        let addr = ListenerAddr::Unix(self.ip, path.to_path_buf());
+        if net.listeners.contains_key(&addr) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "address already in use"));
+        }
+        Ok(SimListener::new(&mut net, self.net.clone(), addr))
+    }
+
+    /// Connect a unix stream to a listener at `path` on this host.
+    #[doc(hidden)]
+    pub fn unix_connect(&self, path: &Path) -> io::Result<SimStream> {
+        let net = self.net.shared.lock();
+        let addr = ListenerAddr::Unix(self.ip, path.to_path_buf());
+        let listener = match net.listeners.get(&addr) {
+            Some(listener) => listener.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused")),
+        };
+
+        let conn = Conn::new(None, net.buffer_size);
+        listener.lock().push(conn.clone(), None, None);
+        Ok(SimStream::new(Some(self.net.clone()), conn, 0, None, None))
+    }
+}
+
+/// Create a pair of connected streams outside of any network.
+///
+/// Backend of `mem::duplex()`: like a unix stream, with each direction
+/// buffering at most `capacity` bytes.
+#[doc(hidden)]
+pub fn duplex(capacity: usize) -> (SimStream, SimStream) {
+    let conn = Conn::new(None, capacity);
+    (SimStream::new(None, conn.clone(), 0, None, None),
+     SimStream::new(None, conn, 1, None, None))
+}
+
+/// One direction of a stream
+struct Pipe {
+    segments: VecDeque<Segment>,
+    buffered: usize,
+    capacity: usize,
+    /// Writing end was shut down
+    write_closed: bool,
+    /// Reading end was shut down
+    read_closed: bool,
+}
+
+struct Segment {
+    deliver_at: SteadyTime,
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Pipe {
+    fn new(capacity: usize) -> Self {
+        Pipe {
+            segments: VecDeque::new(),
+            buffered: 0,
+            capacity: capacity,
+            write_closed: false,
+            read_closed: false,
+        }
+    }
+}
+
+/// When will an event source be readable
+enum Readiness {
+    Now,
+    At(SteadyTime),
+    Never,
+}
+
+/// Shared state of a stream connection
+///
+/// Side `0` is the connecting end, side `1` the accepting one. `pipes[i]`
+/// carries data to side `i`.
+struct Conn {
+    /// Hosts of both sides; `None` for unix and in-memory streams
+    hosts: Option<[IpAddr; 2]>,
+    pipes: [Pipe; 2],
+    wakers: [Waker; 2],
+    is_reset: bool,
+}
+
+impl Conn {
+    fn new(hosts: Option<[IpAddr; 2]>, capacity: usize) -> Arc<Mutex<Conn>> {
+        Arc::new(Mutex::new(Conn {
+            hosts: hosts,
+            pipes: [Pipe::new(capacity), Pipe::new(capacity)],
+            wakers: [Waker::new(), Waker::new()],
+            is_reset: false,
+        }))
+    }
+
+    fn reset(&mut self) {
+        self.is_reset = true;
+        for waker in self.wakers.iter_mut() {
+            waker.wake(EventSet::readable() | EventSet::writable());
+        }
+    }
+
+    fn read_readiness(&self, side: usize, now: SteadyTime) -> Readiness {
+        let pipe = &self.pipes[side];
+        if self.is_reset || pipe.read_closed {
+            return Readiness::Now;
+        }
+        match pipe.segments.front() {
+            Some(segment) if segment.deliver_at <= now => Readiness::Now,
+            Some(segment) => Readiness::At(segment.deliver_at),
+            None if pipe.write_closed => Readiness::Now,
+            None => Readiness::Never,
+        }
+    }
+
+    fn is_writable(&self, side: usize) -> bool {
+        let pipe = &self.pipes[1 - side];
+        self.is_reset || pipe.write_closed || pipe.read_closed || pipe.buffered < pipe.capacity
+    }
+}
+
+/// Simulated stream (TCP or unix)
+///
+/// Backend of `tcp::TcpStream` and `unix::UnixStream` on a simulated host,
+/// and of `mem::MemStream`.
+#[doc(hidden)]
+pub struct SimStream {
+    /// `None` for in-memory streams
+    net: Option<SimNetwork>,
+    conn: Arc<Mutex<Conn>>,
+    side: usize,
+    local: Option<SocketAddr>,
+    peer: Option<SocketAddr>,
+    clock: Clock,
+}
+
+impl SimStream {
+    fn new(net: Option<SimNetwork>,
+           conn: Arc<Mutex<Conn>>,
+           side: usize,
+           local: Option<SocketAddr>,
+           peer: Option<SocketAddr>)
+           -> Self {
+        SimStream {
+            net: net,
+            conn: conn,
+            side: side,
+            local: local,
+            peer: peer,
+            clock: Clock::current(),
+        }
+    }
+
+    /// Local address of connection.
+    ///
+    /// Unix streams have no address.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.local.ok_or_else(no_inet_addr)
+    }
+
+    /// Peer address of connection.
+    ///
+    /// Unix streams have no address.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.peer.ok_or_else(no_inet_addr)
+    }
+
+    /// Shutdown the connection.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let mut conn = self.conn.lock();
+        let side = self.side;
+        match how {
+            Shutdown::Read => conn.pipes[side].read_closed = true,
+            Shutdown::Write => conn.pipes[1 - side].write_closed = true,
+            Shutdown::Both => {
+                conn.pipes[side].read_closed = true;
+                conn.pipes[1 - side].write_closed = true;
+            }
+        }
+        conn.wakers[1 - side].wake(EventSet::readable() | EventSet::writable());
+        Ok(())
+    }
+
+    fn try_read(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let now = self.clock.now();
+        let side = self.side;
+        let mut conn = self.conn.lock();
+
+        if conn.is_reset {
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset"));
+        }
+
+        let read = {
+            let pipe = &mut conn.pipes[side];
+            if pipe.read_closed {
+                return Ok(Some(0));
+            }
+
+            let mut read = 0;
+            while read < buf.len() {
+                let consumed = match pipe.segments.front_mut() {
+                    Some(segment) => {
+                        if segment.deliver_at > now {
+                            break;
+                        }
+                        let len = cmp::min(buf.len() - read, segment.data.len() - segment.pos);
+                        buf[read..read + len]
+                            .clone_from_slice(&segment.data[segment.pos..segment.pos + len]);
+                        segment.pos += len;
+                        read += len;
+                        segment.pos == segment.data.len()
+                    }
+                    None => break,
+                };
+                if consumed {
+                    pipe.segments.pop_front();
+                }
+            }
+            pipe.buffered -= read;
+
+            if read == 0 && !buf.is_empty() {
+                return if pipe.segments.is_empty() && pipe.write_closed {
+                    Ok(Some(0))
+                } else {
+                    Ok(None)
+                };
+            }
+            read
+        };
+
+        conn.wakers[1 - side].wake(EventSet::writable());
+        Ok(Some(read))
+    }
+
+    fn try_write(&self, buf: &[u8]) -> io::Result<Option<usize>> {
+        let now = self.clock.now();
+        let side = self.side;
+        let peer = 1 - side;
+        let mut net = self.net.as_ref().map(|net| net.shared.lock());
+        let mut conn = self.conn.lock();
+
+        if conn.is_reset {
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset"));
+        }
+        if conn.pipes[peer].write_closed || conn.pipes[peer].read_closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"));
+        }
+        if buf.is_empty() {
+            return Ok(Some(0));
+        }
+
+        let len = cmp::min(buf.len(), conn.pipes[peer].capacity - conn.pipes[peer].buffered);
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let mut deliver_at = now;
+        if let Some(hosts) = conn.hosts {
+            let net = net.as_mut().expect("SimStream: network stream without network");
+            match net.transmit(hosts[side], hosts[peer], len, now) {
+                Some(transmission) => {
+                    deliver_at = transmission.deliver_at;
+                    if transmission.lost {
+                        // Retransmitted after a round-trip time
+                        let rtt = cmp::max(2 * transmission.link.latency_ms, 1);
+                        deliver_at = deliver_at + Duration::milliseconds(rtt);
+                    }
+                }
+                None => {
+                    conn.reset();
+                    return Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset"));
+                }
+            }
+        }
+
+        let was_empty = {
+            let pipe = &mut conn.pipes[peer];
+            let was_empty = pipe.segments.is_empty();
+            if let Some(last) = pipe.segments.back() {
+                // Streams are ordered
+                if last.deliver_at > deliver_at {
+                    deliver_at = last.deliver_at;
+                }
+            }
+            pipe.segments.push_back(Segment {
+                deliver_at: deliver_at,
+                data: buf[..len].to_vec(),
+                pos: 0,
+            });
+            pipe.buffered += len;
+            was_empty
+        };
+
+        if was_empty {
+            // Reader will set up a timeout for the segment when re-registering
+            conn.wakers[peer].wake(EventSet::readable());
+        }
+        Ok(Some(len))
+    }
+}
+
+fn no_inet_addr() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "unix streams have no inet address")
+}
+
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "would block")
+}
+
+impl io::Read for SimStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.try_read(buf)).ok_or_else(would_block)
+    }
+}
+
+impl io::Write for SimStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.try_write(buf)).ok_or_else(would_block)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl EventSourceTrait for SimStream {
+    fn register(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        trace!("SimStream({}): register", token.as_usize());
+        let mut conn = self.conn.lock();
+        conn.wakers[self.side].register(event_loop, token, interest);
+
+        let mut ready = EventSet::none();
+        if interest.is_readable() {
+            match conn.read_readiness(self.side, self.clock.now()) {
+                Readiness::Now => ready = ready | EventSet::readable(),
+                Readiness::At(deliver_at) => self.clock.wake_at(event_loop, token, deliver_at),
+                Readiness::Never => {}
+            }
+        }
+        if interest.is_writable() && conn.is_writable(self.side) {
+            ready = ready | EventSet::writable();
+        }
+
+        if !ready.is_none() {
+            trace!("SimStream({}): ready; self notify", token.as_usize());
+            conn.wakers[self.side].wake(ready);
+        }
+    }
+
+    fn reregister(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        self.register(event_loop, token, interest)
+    }
+
+    fn deregister(&self, _event_loop: &mut EventLoop<Handler>, token: Token) {
+        trace!("SimStream({}): deregister", token.as_usize());
+        self.conn.lock().wakers[self.side].deregister();
+        self.clock.cancel(token);
+    }
+
+    fn should_resume(&self) -> bool {
+        true
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        let mut conn = self.conn.lock();
+        let side = self.side;
+        conn.pipes[side].read_closed = true;
+        conn.pipes[side].segments.clear();
+        conn.pipes[side].buffered = 0;
+        conn.pipes[1 - side].write_closed = true;
+        conn.wakers[side].deregister();
+        conn.wakers[1 - side].wake(EventSet::readable() | EventSet::writable());
+    }
+}
+
+/// Simulated stream listener (TCP or unix)
+///
+/// Backend of `tcp::TcpListener` and `unix::UnixListener` on a simulated
+/// host.
+#[doc(hidden)]
+pub struct SimListener {
+    net: SimNetwork,
+    shared: Arc<Mutex<ListenerShared>>,
+    addr: ListenerAddr,
+    clock: Clock,
+}
+
+/// Connection waiting to be accepted
+struct PendingConn {
+    conn: Arc<Mutex<Conn>>,
+    local: Option<SocketAddr>,
+    peer: Option<SocketAddr>,
+}
+
+struct ListenerShared {
+    backlog: VecDeque<PendingConn>,
+    waker: Waker,
+}
+
+impl ListenerShared {
+    fn push(&mut self, conn: Arc<Mutex<Conn>>, local: Option<SocketAddr>, peer: Option<SocketAddr>) {
+        self.backlog.push_back(PendingConn {
+            conn: conn,
+            local: local,
+            peer: peer,
+        });
+        self.waker.wake(EventSet::readable());
+    }
+}
+
+impl SimListener {
+    fn new(net_shared: &mut NetShared, net: SimNetwork, addr: ListenerAddr) -> Self {
+        let shared = Arc::new(Mutex::new(ListenerShared {
+            backlog: VecDeque::new(),
+            waker: Waker::new(),
+        }));
+        net_shared.listeners.insert(addr.clone(), shared.clone());
+
+        SimListener {
+            net: net,
+            shared: shared,
+            addr: addr,
+            clock: Clock::current(),
+        }
+    }
+
+    /// Local address
+    ///
+    /// Unix listeners have no address.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.addr {
+            ListenerAddr::Tcp(addr) => Ok(addr),
+            ListenerAddr::Unix(..) => Err(no_inet_addr()),
+        }
+    }
+}
+
+impl mio_orig::TryAccept for SimListener {
+    type Output = SimStream;
+
+    fn accept(&self) -> io::Result<Option<SimStream>> {
+        let pending = self.shared.lock().backlog.pop_front();
+        Ok(pending.map(|pending| {
+            SimStream {
+                net: Some(self.net.clone()),
+                conn: pending.conn,
+                side: 1,
+                local: pending.local,
+                peer: pending.peer,
+                clock: self.clock.clone(),
+            }
+        }))
+    }
+}
+
+impl EventSourceTrait for SimListener {
+    fn register(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        trace!("SimListener({}): register", token.as_usize());
+        let mut lock = self.shared.lock();
+        lock.waker.register(event_loop, token, interest);
+        if !lock.backlog.is_empty() {
+            lock.waker.wake(EventSet::readable());
+        }
+    }
+
+    fn reregister(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        self.register(event_loop, token, interest)
+    }
+
+    fn deregister(&self, _event_loop: &mut EventLoop<Handler>, token: Token) {
+        trace!("SimListener({}): deregister", token.as_usize());
+        self.shared.lock().waker.deregister();
+    }
+
+    fn should_resume(&self) -> bool {
+        true
+    }
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        self.net.shared.lock().listeners.remove(&self.addr);
+        for pending in self.shared.lock().backlog.drain(..) {
+            pending.conn.lock().reset();
+        }
+    }
+}
+
+/// Simulated datagram socket
+///
+/// Backend of `udp::UdpSocket` on a simulated host.
+#[doc(hidden)]
+pub struct SimUdpSocket {
+    host: SimHost,
+    shared: Arc<Mutex<UdpShared>>,
+    /// `None` until bound
+    addr: Option<SocketAddr>,
+    clock: Clock,
+}
+
+struct Datagram {
+    deliver_at: SteadyTime,
+    from: SocketAddr,
+    data: Vec<u8>,
+}
+
+struct UdpShared {
+    /// Datagrams in flight, ordered by delivery time
+    queue: Vec<Datagram>,
+    waker: Waker,
+}
+
+impl UdpShared {
+    fn read_readiness(&self, now: SteadyTime) -> Readiness {
+        match self.queue.first() {
+            Some(datagram) if datagram.deliver_at <= now => Readiness::Now,
+            Some(datagram) => Readiness::At(datagram.deliver_at),
+            None => Readiness::Never,
+        }
+    }
+}
+
+impl SimUdpSocket {
+    /// Bind the socket to `addr` (port `0` picks a free one).
+    pub fn bind(&mut self, addr: &SocketAddr) -> io::Result<()> {
+        if self.addr.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "socket already bound"));
+        }
+        let ip = try!(self.host.local_ip(addr.ip()));
+        let mut net = self.host.net.shared.lock();
+        let addr = try!(net.bind_port(ip, addr.port(), &|net: &NetShared, addr: SocketAddr| {
+            !net.udp.contains_key(&addr)
+        }));
+        net.udp.insert(addr, self.shared.clone());
+        self.addr = Some(addr);
+        Ok(())
+    }
+
+    /// Local address of the socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.addr.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "socket not bound"))
+    }
+
+    /// Try reading a datagram into a buffer.
+    ///
+    /// Datagrams longer than `buf` are truncated.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        let now = self.clock.now();
+        let mut lock = self.shared.lock();
+        match lock.read_readiness(now) {
+            Readiness::Now => {}
+            _ => return Ok(None),
+        }
+
+        let datagram = lock.queue.remove(0);
+        let len = cmp::min(buf.len(), datagram.data.len());
+        buf[..len].clone_from_slice(&datagram.data[..len]);
+        Ok(Some((len, datagram.from)))
+    }
+
+    /// Send a datagram to `target`, binding the socket first if needed.
+    ///
+    /// Like with a real network, datagrams that are lost or have no
+    /// receiver are silently dropped. Never blocks.
+    pub fn send_to(&mut self, buf: &[u8], target: &SocketAddr) -> io::Result<Option<usize>> {
+        let from = match self.addr {
+            Some(addr) => addr,
+            None => {
+                let any = SocketAddr::new(self.host.ip, 0);
+                try!(self.bind(&any));
+                try!(self.local_addr())
+            }
+        };
+        let now = self.clock.now();
+        let mut net = self.host.net.shared.lock();
+
+        let transmission = match net.transmit(from.ip(), target.ip(), buf.len(), now) {
+            Some(transmission) => transmission,
+            None => {
+                trace!("SimUdpSocket: {} -> {}: partitioned", from, target);
+                return Ok(Some(buf.len()));
+            }
+        };
+        if transmission.lost {
+            trace!("SimUdpSocket: {} -> {}: lost", from, target);
+            return Ok(Some(buf.len()));
+        }
+        let receiver = match net.udp.get(target) {
+            Some(receiver) => receiver.clone(),
+            None => return Ok(Some(buf.len())),
+        };
+
+        let mut deliver_at = transmission.deliver_at;
+        if transmission.reordered {
+            deliver_at = deliver_at + Duration::milliseconds(cmp::max(transmission.link.latency_ms, 1));
+        }
+
+        let mut receiver = receiver.lock();
+        let i = receiver.queue
+                        .iter()
+                        .position(|datagram| datagram.deliver_at > deliver_at)
+                        .unwrap_or(receiver.queue.len());
+        receiver.queue.insert(i, Datagram {
+            deliver_at: deliver_at,
+            from: from,
+            data: buf.to_vec(),
+        });
+        if i == 0 {
+            // Reader will set up a timeout for the datagram when re-registering
+            receiver.waker.wake(EventSet::readable());
+        }
+        Ok(Some(buf.len()))
+    }
+}
+
+impl EventSourceTrait for SimUdpSocket {
+    fn register(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        trace!("SimUdpSocket({}): register", token.as_usize());
+        let mut lock = self.shared.lock();
+        lock.waker.register(event_loop, token, interest);
+
+        let mut ready = EventSet::none();
+        if interest.is_readable() {
+            match lock.read_readiness(self.clock.now()) {
+                Readiness::Now => ready = ready | EventSet::readable(),
+                Readiness::At(deliver_at) => self.clock.wake_at(event_loop, token, deliver_at),
+                Readiness::Never => {}
+            }
+        }
+        if interest.is_writable() {
+            ready = ready | EventSet::writable();
+        }
+
+        if !ready.is_none() {
+            lock.waker.wake(ready);
+        }
+    }
+
+    fn reregister(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        self.register(event_loop, token, interest)
+    }
+
+    fn deregister(&self, _event_loop: &mut EventLoop<Handler>, token: Token) {
+        trace!("SimUdpSocket({}): deregister", token.as_usize());
+        self.shared.lock().waker.deregister();
+        self.clock.cancel(token);
+    }
+
+    fn should_resume(&self) -> bool {
+        true
+    }
+}
+
+impl Drop for SimUdpSocket {
+    fn drop(&mut self) {
+        if let Some(addr) = self.addr {
+            self.host.net.shared.lock().udp.remove(&addr);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+use time::Duration;
+
+/// Number of `Histogram` buckets
+const BUCKETS_NUM: usize = 48;
+
+/// Histogram of values
+///
+/// Buckets are powers of two: bucket `0` counts zeros, bucket `i` counts
+/// values in `[2^(i-1), 2^i)`. Durations are recorded in microseconds.
+/// Recording is lock-free, so it can be shared between threads.
+pub struct Histogram {
+    buckets: Vec<AtomicUsize>,
+}
+
+impl Histogram {
+    /// Create an empty histogram.
+    pub fn new() -> Self {
+        Histogram { buckets: (0..BUCKETS_NUM).map(|_| AtomicUsize::new(0)).collect() }
+    }
+
+    fn bucket_i(value: u64) -> usize {
+        let bits = 64 - value.leading_zeros() as usize;
+        if bits >= BUCKETS_NUM {
+            BUCKETS_NUM - 1
+        } else {
+            bits
+        }
+    }
+
+    /// Upper bound of bucket `i`.
+    fn bucket_bound(i: usize) -> u64 {
+        1 << i
+    }
+
+    /// Record a value.
+    pub fn record(&self, value: u64) {
+        self.buckets[Histogram::bucket_i(value)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a duration, in microseconds.
+    pub fn record_duration(&self, d: Duration) {
+        let us = d.num_microseconds().unwrap_or(i64::max_value());
+        self.record(if us < 0 { 0 } else { us as u64 });
+    }
+
+    /// Total number of recorded values.
+    pub fn count(&self) -> usize {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).fold(0, |acc, c| acc + c)
+    }
+
+    /// Non-empty buckets, as pairs of (exclusive) upper bound and count.
+    pub fn buckets(&self) -> Vec<(u64, usize)> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (Histogram::bucket_bound(i), b.load(Ordering::Relaxed)))
+            .filter(|&(_, count)| count > 0)
+            .collect()
+    }
+
+    /// Upper bound of the bucket containing given percentile (`0.0 - 100.0`).
+    ///
+    /// Returns `None` if nothing was recorded.
+    pub fn percentile(&self, p: f64) -> Option<u64> {
+        let counts: Vec<usize> = self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect();
+        let total = counts.iter().fold(0, |acc, c| acc + c);
+        if total == 0 {
+            return None;
+        }
+
+        let rank = ((p / 100.0) * total as f64).ceil() as usize;
+        let mut seen = 0;
+        for (i, count) in counts.iter().enumerate() {
+            seen += *count;
+            if seen >= rank && *count > 0 {
+                return Some(Histogram::bucket_bound(i));
+            }
+        }
+        Some(Histogram::bucket_bound(BUCKETS_NUM - 1))
+    }
+}
+
+/// Runtime statistics of a mioco instance
+///
+/// See `Mioco::stats()` and `mioco::stats()`.
+pub struct Stats {
+    run_time: Histogram,
+    sched_latency: Histogram,
+    stack_usage: Mutex<HashMap<String, Arc<Histogram>>>,
+}
+
+impl Stats {
+    /// Create empty statistics.
+    pub fn new() -> Self {
+        Stats {
+            run_time: Histogram::new(),
+            sched_latency: Histogram::new(),
+            stack_usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Total wall-clock (not CPU) run time of finished coroutines, in
+    /// microseconds.
+    ///
+    /// Only collected when enabled with `Config::set_timing_stats()`.
+    pub fn run_time(&self) -> &Histogram {
+        &self.run_time
+    }
+
+    /// Time between a coroutine becoming ready and actually being resumed,
+    /// in microseconds, recorded for every resume.
+    ///
+    /// Only collected when enabled with `Config::set_timing_stats()`.
+    pub fn sched_latency(&self) -> &Histogram {
+        &self.sched_latency
+    }
+
+    /// Stack high-water marks of finished coroutines in bytes, by
+    /// coroutine name.
+    ///
+    /// Only collected when enabled with `Config::set_stack_usage_tracking()`.
+    /// Coroutines without a name are reported as `"<unnamed>"`.
+    pub fn stack_usage(&self) -> Vec<(String, Arc<Histogram>)> {
+        self.stack_usage
+            .lock()
+            .iter()
+            .map(|(name, histogram)| (name.clone(), histogram.clone()))
+            .collect()
+    }
+
+    /// Record stack high-water mark of a coroutine.
+    #[doc(hidden)]
+    pub fn record_stack_usage(&self, name: &str, used: usize) {
+        let histogram = self.stack_usage
+                            .lock()
+                            .entry(name.to_owned())
+                            .or_insert_with(|| Arc::new(Histogram::new()))
+                            .clone();
+        histogram.record(used as u64);
+    }
+}
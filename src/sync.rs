@@ -1,78 +1,439 @@
+use super::{RW, in_coroutine, select_wait};
+use super::evented::{EventSourceTrait, RcEventSource, Evented, EventedImpl};
+use super::mio_orig::{EventLoop, Token, EventSet};
+use super::thread::Handler;
+use super::timer::{Clock, Timer};
+use super::wake::{Notification, Waker};
+
+use spin;
+use time::{SteadyTime, Duration};
+
 use std::sync as ssync;
+use std::collections::VecDeque;
 use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration as StdDuration;
+
+/// One-time wakeup of a coroutine or thread blocked on a synchronization
+/// primitive
+struct Signal {
+    state: spin::Mutex<SignalState>,
+}
+
+struct SignalState {
+    signaled: bool,
+    waker: Waker,
+    thread: Option<thread::Thread>,
+}
+
+impl Signal {
+    fn new() -> Arc<Signal> {
+        Arc::new(Signal {
+            state: spin::Mutex::new(SignalState {
+                signaled: false,
+                waker: Waker::new(),
+                thread: None,
+            }),
+        })
+    }
+
+    /// Mark signaled, returning the wakeup of the waiter.
+    ///
+    /// Call it under the lock of the primitive, so a waiter that timed out
+    /// can tell if it was signaled before giving up.
+    fn signal(&self) -> Wakeup {
+        let mut state = self.state.lock();
+        state.signaled = true;
+        Wakeup {
+            notification: state.waker.take_notification(EventSet::readable()),
+            thread: state.thread.take(),
+        }
+    }
+
+    fn is_signaled(&self) -> bool {
+        self.state.lock().signaled
+    }
+
+    /// Block until signaled, or until `deadline`.
+    ///
+    /// Blocks coroutine, or parks the thread outside of coroutines.
+    /// Returns `false` on timeout.
+    fn wait(signal: &Arc<Signal>, deadline: Option<SteadyTime>) -> bool {
+        if in_coroutine() {
+            let event = SignalEvent(RcEventSource::new(SignalCore(signal.clone())));
+            let mut timer = deadline.map(|deadline| {
+                let mut timer = Timer::new();
+                timer.set_timeout_absolute(deadline);
+                timer
+            });
+
+            loop {
+                if signal.is_signaled() {
+                    return true;
+                }
+
+                match timer {
+                    None => event.block_on(RW::read()),
+                    Some(ref mut timer) => {
+                        if timer.try_read().is_some() {
+                            return false;
+                        }
+                        unsafe {
+                            event.select_add(RW::read());
+                            timer.select_add(RW::read());
+                        }
+                        let _ = select_wait();
+                    }
+                }
+            }
+        }
+
+        loop {
+            {
+                let mut state = signal.state.lock();
+                if state.signaled {
+                    return true;
+                }
+                state.thread = Some(thread::current());
+            }
+
+            match deadline {
+                None => thread::park(),
+                Some(deadline) => {
+                    let remaining = (deadline - SteadyTime::now()).num_milliseconds();
+                    if remaining <= 0 {
+                        return signal.is_signaled();
+                    }
+                    thread::park_timeout(StdDuration::from_millis(remaining as u64));
+                }
+            }
+        }
+    }
+}
 
-mod mioco {
-    pub use super::super::*;
+/// Wakeup of a signaled waiter
+///
+/// Taken under the lock of the primitive it waits on, and delivered with
+/// `wake()` after releasing it: waking a coroutine can spin until its
+/// event loop has room for the notification.
+#[must_use]
+struct Wakeup {
+    notification: Option<Notification>,
+    thread: Option<thread::Thread>,
+}
+
+impl Wakeup {
+    fn wake(self) {
+        if let Some(notification) = self.notification {
+            notification.send();
+        }
+        if let Some(thread) = self.thread {
+            thread.unpark();
+        }
+    }
+}
+
+/// Deliver `wakeups`, after releasing the lock they were taken under.
+fn wake_all(wakeups: Vec<Wakeup>) {
+    for wakeup in wakeups {
+        wakeup.wake();
+    }
+}
+
+struct SignalEvent(RcEventSource<SignalCore>);
+
+struct SignalCore(Arc<Signal>);
+
+impl EventedImpl for SignalEvent {
+    type Raw = SignalCore;
+
+    fn shared(&self) -> &RcEventSource<SignalCore> {
+        &self.0
+    }
+}
+
+impl EventSourceTrait for SignalCore {
+    fn register(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        trace!("Signal({}): register", token.as_usize());
+        let notification = {
+            let mut state = self.0.state.lock();
+            state.waker.register(event_loop, token, interest);
+
+            if state.signaled {
+                state.waker.take_notification(EventSet::readable())
+            } else {
+                None
+            }
+        };
+
+        if let Some(notification) = notification {
+            notification.send();
+        }
+    }
+
+    fn reregister(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        self.register(event_loop, token, interest)
+    }
+
+    fn deregister(&self, _event_loop: &mut EventLoop<Handler>, token: Token) {
+        trace!("Signal({}): deregister", token.as_usize());
+        self.0.state.lock().waker.deregister();
+    }
+
+    fn should_resume(&self) -> bool {
+        self.0.is_signaled()
+    }
+}
+
+/// Deadline `timeout_ms` from now, in time of the current mioco instance
+fn deadline_in(timeout_ms: i64) -> SteadyTime {
+    Clock::current().now() + Duration::milliseconds(timeout_ms)
+}
+
+/// Remove `signal` from a wait queue.
+fn remove_signal(waiting: &mut VecDeque<Arc<Signal>>, signal: &Arc<Signal>) {
+    let signal = &**signal as *const Signal;
+    if let Some(i) = waiting.iter().position(|s| &**s as *const Signal == signal) {
+        waiting.remove(i);
+    }
+}
+
+/// Coroutine waiting for a lock
+struct LockWaiter {
+    write: bool,
+    signal: Arc<Signal>,
+}
+
+/// Ownership of a lock, and coroutines waiting for it
+///
+/// The lock is handed off directly to waiters, in order they arrived, so
+/// newcomers can't overtake them.
+struct LockState {
+    readers: usize,
+    writer: bool,
+    prefer_writers: bool,
+    waiting: VecDeque<LockWaiter>,
+}
+
+impl LockState {
+    fn new(prefer_writers: bool) -> Self {
+        LockState {
+            readers: 0,
+            writer: false,
+            prefer_writers: prefer_writers,
+            waiting: VecDeque::new(),
+        }
+    }
+
+    fn writers_waiting(&self) -> bool {
+        self.waiting.iter().any(|w| w.write)
+    }
+
+    /// Try taking the lock, without overtaking anyone waiting.
+    fn try_acquire(&mut self, write: bool) -> bool {
+        let free = if write {
+            !self.writer && self.readers == 0
+        } else {
+            !self.writer
+        };
+        let overtakes = if self.prefer_writers {
+            self.writers_waiting()
+        } else {
+            !self.waiting.is_empty()
+        };
+
+        if !free || overtakes {
+            return false;
+        }
+
+        if write {
+            self.writer = true;
+        } else {
+            self.readers += 1;
+        }
+        true
+    }
+
+    /// Returns wakeups of waiters the lock was handed off to.
+    fn release(&mut self, write: bool) -> Vec<Wakeup> {
+        if write {
+            self.writer = false;
+        } else {
+            self.readers -= 1;
+        }
+        self.hand_off()
+    }
+
+    /// Give the lock to waiters it is free for.
+    fn hand_off(&mut self) -> Vec<Wakeup> {
+        let mut wakeups = Vec::new();
+
+        if self.prefer_writers && self.writers_waiting() {
+            if !self.writer && self.readers == 0 {
+                let i = self.waiting.iter().position(|w| w.write).unwrap();
+                let waiter = self.waiting.remove(i).unwrap();
+                self.writer = true;
+                wakeups.push(waiter.signal.signal());
+            }
+            return wakeups;
+        }
+
+        loop {
+            let write = match self.waiting.front() {
+                Some(waiter) => waiter.write,
+                None => return wakeups,
+            };
+            if self.writer || (write && self.readers > 0) {
+                return wakeups;
+            }
+
+            let waiter = self.waiting.pop_front().unwrap();
+            if write {
+                self.writer = true;
+            } else {
+                self.readers += 1;
+            }
+            wakeups.push(waiter.signal.signal());
+            if write {
+                return wakeups;
+            }
+        }
+    }
+}
+
+/// Release `state` lock, waking up waiters it was handed off to after
+/// unlocking.
+fn release(state: &spin::Mutex<LockState>, write: bool) {
+    let wakeups = state.lock().release(write);
+    wake_all(wakeups);
+}
+
+/// Take `state` lock for reading or writing, blocking until it's granted.
+fn acquire(state: &spin::Mutex<LockState>, write: bool) {
+    let signal = {
+        let mut state = state.lock();
+        if state.try_acquire(write) {
+            return;
+        }
+        let signal = Signal::new();
+        state.waiting.push_back(LockWaiter {
+            write: write,
+            signal: signal.clone(),
+        });
+        signal
+    };
+
+    // Lock is handed off to us before we are woken up.
+    Signal::wait(&signal, None);
+}
+
+fn map_lock_result<G, U, F>(result: ssync::LockResult<G>, f: F) -> ssync::LockResult<U>
+    where F: FnOnce(G) -> U
+{
+    match result {
+        Ok(guard) => Ok(f(guard)),
+        Err(poisoned) => Err(ssync::PoisonError::new(f(poisoned.into_inner()))),
+    }
+}
+
+fn map_try_lock_result<G, U, F>(result: ssync::TryLockResult<G>,
+                                f: F)
+                                -> ssync::TryLockResult<U>
+    where F: FnOnce(G) -> U
+{
+    match result {
+        Ok(guard) => Ok(f(guard)),
+        Err(ssync::TryLockError::Poisoned(poisoned)) => {
+            Err(ssync::TryLockError::Poisoned(ssync::PoisonError::new(f(poisoned.into_inner()))))
+        }
+        Err(ssync::TryLockError::WouldBlock) => Err(ssync::TryLockError::WouldBlock),
+    }
 }
 
 /// A reader-writer lock
 ///
-/// Based on `std::sync::RwLock`. Calls `mioco::yield_now()` on contention.
-#[derive(Debug)]
+/// Based on `std::sync::RwLock`. Blocked coroutines wait in a queue, and
+/// are woken up, through their event loops, when the lock is handed off
+/// to them: in order of arrival, or with writers first for locks created
+/// with `new_prefer_writers()`. Outside of coroutines, threads are parked.
 pub struct RwLock<T: ?Sized> {
+    state: spin::Mutex<LockState>,
     lock: ssync::RwLock<T>,
 }
 
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.lock.fmt(f)
+    }
+}
+
 impl<T> RwLock<T> {
     /// Creates a new instance of an RwLock<T> which is unlocked.
     pub fn new(t: T) -> Self {
-        RwLock { lock: ssync::RwLock::new(t) }
+        RwLock {
+            state: spin::Mutex::new(LockState::new(false)),
+            lock: ssync::RwLock::new(t),
+        }
+    }
+
+    /// Like `new()`, but writers waiting for the lock go before any
+    /// readers, so they can't be starved by a stream of readers.
+    pub fn new_prefer_writers(t: T) -> Self {
+        RwLock {
+            state: spin::Mutex::new(LockState::new(true)),
+            lock: ssync::RwLock::new(t),
+        }
     }
 }
 
 impl<T: ?Sized> RwLock<T> {
     /// Get a reference to raw `std::sync::RwLock`.
     ///
-    /// Use it to perform operations outside of mioco coroutines.
+    /// Use it to perform operations outside of mioco, only when no
+    /// coroutine uses the lock: it bypasses the wait queue.
     pub fn native_lock(&self) -> &ssync::RwLock<T> {
         &self.lock
     }
 
     /// Locks this rwlock with shared read access, blocking the current
     /// coroutine until it can be acquired.
-    pub fn read(&self) -> ssync::LockResult<ssync::RwLockReadGuard<T>> {
-        loop {
-            match self.lock.try_read() {
-                Ok(guard) => return Ok(guard),
-                Err(try_error) => {
-                    match try_error {
-                        ssync::TryLockError::Poisoned(p_err) => {
-                            return Err(p_err);
-                        }
-                        ssync::TryLockError::WouldBlock => mioco::yield_now(),
-                    }
-                }
-            }
-        }
+    pub fn read(&self) -> ssync::LockResult<RwLockReadGuard<T>> {
+        acquire(&self.state, false);
+        map_lock_result(self.lock.read(), |guard| RwLockReadGuard::new(self, guard))
     }
 
     /// Attempts to acquire this rwlock with shared read access.
-    pub fn try_read(&self) -> ssync::TryLockResult<ssync::RwLockReadGuard<T>> {
-        self.lock.try_read()
+    pub fn try_read(&self) -> ssync::TryLockResult<RwLockReadGuard<T>> {
+        if !self.state.lock().try_acquire(false) {
+            return Err(ssync::TryLockError::WouldBlock);
+        }
+        let result = self.lock.try_read();
+        if let Err(ssync::TryLockError::WouldBlock) = result {
+            release(&self.state, false);
+        }
+        map_try_lock_result(result, |guard| RwLockReadGuard::new(self, guard))
     }
 
     /// Locks this rwlock with exclusive write access, blocking the current
     /// coroutine until it can be acquired.
-    pub fn write(&self) -> ssync::LockResult<ssync::RwLockWriteGuard<T>> {
-        loop {
-            match self.lock.try_write() {
-                Ok(guard) => return Ok(guard),
-                Err(try_error) => {
-                    match try_error {
-                        ssync::TryLockError::Poisoned(p_err) => {
-                            return Err(p_err);
-                        }
-                        ssync::TryLockError::WouldBlock => mioco::yield_now(),
-                    }
-                }
-            }
-        }
+    pub fn write(&self) -> ssync::LockResult<RwLockWriteGuard<T>> {
+        acquire(&self.state, true);
+        map_lock_result(self.lock.write(), |guard| RwLockWriteGuard::new(self, guard))
     }
 
 
     /// Attempts to lock this rwlock with exclusive write access.
-    pub fn try_write(&self) -> ssync::TryLockResult<ssync::RwLockWriteGuard<T>> {
-        self.lock.try_write()
+    pub fn try_write(&self) -> ssync::TryLockResult<RwLockWriteGuard<T>> {
+        if !self.state.lock().try_acquire(true) {
+            return Err(ssync::TryLockError::WouldBlock);
+        }
+        let result = self.lock.try_write();
+        if let Err(ssync::TryLockError::WouldBlock) = result {
+            release(&self.state, true);
+        }
+        map_try_lock_result(result, |guard| RwLockWriteGuard::new(self, guard))
     }
 
     /// Determines whether the lock is poisoned.
@@ -81,10 +442,79 @@ impl<T: ?Sized> RwLock<T> {
     }
 }
 
+/// Shared read access to a `RwLock`; released on drop
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    guard: Option<ssync::RwLockReadGuard<'a, T>>,
+}
+
+impl<'a, T: ?Sized> RwLockReadGuard<'a, T> {
+    fn new(lock: &'a RwLock<T>, guard: ssync::RwLockReadGuard<'a, T>) -> Self {
+        RwLockReadGuard {
+            lock: lock,
+            guard: Some(guard),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        release(&self.lock.state, false);
+    }
+}
+
+/// Exclusive write access to a `RwLock`; released on drop
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    guard: Option<ssync::RwLockWriteGuard<'a, T>>,
+}
+
+impl<'a, T: ?Sized> RwLockWriteGuard<'a, T> {
+    fn new(lock: &'a RwLock<T>, guard: ssync::RwLockWriteGuard<'a, T>) -> Self {
+        RwLockWriteGuard {
+            lock: lock,
+            guard: Some(guard),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        release(&self.lock.state, true);
+    }
+}
+
 /// A Mutex
 ///
-/// Based on `std::sync::Mutex`. Calls `mioco::yield_now()` on contention.
+/// Based on `std::sync::Mutex`. Blocked coroutines wait in a queue, and
+/// get the lock in order of arrival, woken up through their event loops.
+/// Outside of coroutines, threads are parked.
 pub struct Mutex<T: ?Sized> {
+    state: spin::Mutex<LockState>,
     lock: ssync::Mutex<T>,
 }
 
@@ -97,37 +527,457 @@ impl<T: ?Sized + fmt::Debug + 'static> fmt::Debug for Mutex<T> {
 impl<T> Mutex<T> {
     /// Creates a new instance of an Mutex<T> which is unlocked.
     pub fn new(t: T) -> Self {
-        Mutex { lock: ssync::Mutex::new(t) }
+        Mutex {
+            state: spin::Mutex::new(LockState::new(false)),
+            lock: ssync::Mutex::new(t),
+        }
     }
 }
 
 impl<T: ?Sized> Mutex<T> {
     /// Get a reference to raw `std::sync::Mutex`.
     ///
-    /// Use it to perform operations outside of mioco coroutines.
+    /// Use it to perform operations outside of mioco, only when no
+    /// coroutine uses the lock: it bypasses the wait queue.
     pub fn native_lock(&self) -> &ssync::Mutex<T> {
         &self.lock
     }
 
     /// Acquire a mutex, blocking the current coroutine until it is able to do so.
-    pub fn lock(&self) -> ssync::LockResult<ssync::MutexGuard<T>> {
-        loop {
-            match self.try_lock() {
-                Ok(guard) => return Ok(guard),
-                Err(try_error) => {
-                    match try_error {
-                        ssync::TryLockError::Poisoned(p_err) => {
-                            return Err(p_err);
-                        }
-                        ssync::TryLockError::WouldBlock => mioco::yield_now(),
-                    }
+    pub fn lock(&self) -> ssync::LockResult<MutexGuard<T>> {
+        acquire(&self.state, true);
+        map_lock_result(self.lock.lock(), |guard| MutexGuard::new(self, guard))
+    }
+
+    /// Attempt to acquire this lock.
+    pub fn try_lock(&self) -> ssync::TryLockResult<MutexGuard<T>> {
+        if !self.state.lock().try_acquire(true) {
+            return Err(ssync::TryLockError::WouldBlock);
+        }
+        let result = self.lock.try_lock();
+        if let Err(ssync::TryLockError::WouldBlock) = result {
+            release(&self.state, true);
+        }
+        map_try_lock_result(result, |guard| MutexGuard::new(self, guard))
+    }
+}
+
+/// Access to data protected by a `Mutex`; unlocked on drop
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a Mutex<T>,
+    guard: Option<ssync::MutexGuard<'a, T>>,
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    fn new(lock: &'a Mutex<T>, guard: ssync::MutexGuard<'a, T>) -> Self {
+        MutexGuard {
+            lock: lock,
+            guard: Some(guard),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        release(&self.lock.state, true);
+    }
+}
+
+/// A condition variable
+///
+/// Works with `Mutex` guards, like `std::sync::Condvar` does with
+/// `std::sync::MutexGuard`. Waiting coroutines are blocked (threads
+/// outside of coroutines parked) and notified in order they started
+/// waiting.
+pub struct Condvar {
+    waiting: spin::Mutex<VecDeque<Arc<Signal>>>,
+}
+
+impl Condvar {
+    /// Create a new condition variable.
+    pub fn new() -> Self {
+        Condvar { waiting: spin::Mutex::new(VecDeque::new()) }
+    }
+
+    /// Unlock `guard` and block until notified, then lock it again.
+    ///
+    /// Spurious wakeups are possible, so check the condition in a loop.
+    pub fn wait<'a, T: ?Sized>(&self,
+                               guard: MutexGuard<'a, T>)
+                               -> ssync::LockResult<MutexGuard<'a, T>> {
+        let signal = Signal::new();
+        self.waiting.lock().push_back(signal.clone());
+
+        let mutex = guard.lock;
+        drop(guard);
+        Signal::wait(&signal, None);
+
+        mutex.lock()
+    }
+
+    /// Like `wait()`, but gives up waiting after `timeout_ms`.
+    ///
+    /// Returns the guard, and `true` if it timed out.
+    pub fn wait_timeout<'a, T: ?Sized>(&self,
+                                       guard: MutexGuard<'a, T>,
+                                       timeout_ms: i64)
+                                       -> ssync::LockResult<(MutexGuard<'a, T>, bool)> {
+        let deadline = deadline_in(timeout_ms);
+        let signal = Signal::new();
+        self.waiting.lock().push_back(signal.clone());
+
+        let mutex = guard.lock;
+        drop(guard);
+        let timed_out = if Signal::wait(&signal, Some(deadline)) {
+            false
+        } else {
+            let mut waiting = self.waiting.lock();
+            remove_signal(&mut waiting, &signal);
+            !signal.is_signaled()
+        };
+
+        map_lock_result(mutex.lock(), |guard| (guard, timed_out))
+    }
+
+    /// Wake up one waiting coroutine.
+    pub fn notify_one(&self) {
+        let wakeup = self.waiting.lock().pop_front().map(|signal| signal.signal());
+        if let Some(wakeup) = wakeup {
+            wakeup.wake();
+        }
+    }
+
+    /// Wake up all waiting coroutines.
+    pub fn notify_all(&self) {
+        let mut wakeups = Vec::new();
+        {
+            let mut waiting = self.waiting.lock();
+            while let Some(signal) = waiting.pop_front() {
+                wakeups.push(signal.signal());
+            }
+        }
+        wake_all(wakeups);
+    }
+}
+
+struct SemaphoreState {
+    permits: usize,
+    waiting: VecDeque<Arc<Signal>>,
+}
+
+/// A counting semaphore
+///
+/// Permits are handed out in order they were asked for; each is returned
+/// when its `SemaphorePermit` is dropped. Waiting coroutines are blocked
+/// (threads outside of coroutines parked).
+pub struct Semaphore {
+    state: spin::Mutex<SemaphoreState>,
+}
+
+impl Semaphore {
+    /// Create a semaphore with `permits` permits available.
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            state: spin::Mutex::new(SemaphoreState {
+                permits: permits,
+                waiting: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Acquire a permit, blocking until one is available.
+    pub fn acquire(&self) -> SemaphorePermit {
+        let signal = match self.acquire_or_wait() {
+            Some(signal) => signal,
+            None => return SemaphorePermit { semaphore: self },
+        };
+
+        // The permit is handed off to us before we are woken up.
+        Signal::wait(&signal, None);
+        SemaphorePermit { semaphore: self }
+    }
+
+    /// Like `acquire()`, but gives up after `timeout_ms`.
+    pub fn acquire_timeout(&self, timeout_ms: i64) -> Option<SemaphorePermit> {
+        let deadline = deadline_in(timeout_ms);
+        let signal = match self.acquire_or_wait() {
+            Some(signal) => signal,
+            None => return Some(SemaphorePermit { semaphore: self }),
+        };
+
+        if !Signal::wait(&signal, Some(deadline)) {
+            let mut state = self.state.lock();
+            if !signal.is_signaled() {
+                remove_signal(&mut state.waiting, &signal);
+                return None;
+            }
+        }
+        Some(SemaphorePermit { semaphore: self })
+    }
+
+    /// Acquire a permit, if one is available right away.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit> {
+        let mut state = self.state.lock();
+        if state.permits > 0 && state.waiting.is_empty() {
+            state.permits -= 1;
+            Some(SemaphorePermit { semaphore: self })
+        } else {
+            None
+        }
+    }
+
+    /// Number of permits available right now.
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Add `n` permits.
+    pub fn add_permits(&self, n: usize) {
+        let mut wakeups = Vec::new();
+        {
+            let mut state = self.state.lock();
+            for _ in 0..n {
+                wakeups.extend(Semaphore::release_locked(&mut state));
+            }
+        }
+        wake_all(wakeups);
+    }
+
+    /// Take a permit, or get in the queue for one.
+    fn acquire_or_wait(&self) -> Option<Arc<Signal>> {
+        let mut state = self.state.lock();
+        if state.permits > 0 && state.waiting.is_empty() {
+            state.permits -= 1;
+            return None;
+        }
+
+        let signal = Signal::new();
+        state.waiting.push_back(signal.clone());
+        Some(signal)
+    }
+
+    /// Returns the wakeup of the waiter the permit was handed off to.
+    fn release_locked(state: &mut SemaphoreState) -> Option<Wakeup> {
+        match state.waiting.pop_front() {
+            Some(signal) => Some(signal.signal()),
+            None => {
+                state.permits += 1;
+                None
+            }
+        }
+    }
+}
+
+/// Permit acquired from a `Semaphore`; returned to it on drop
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Drop for SemaphorePermit<'a> {
+    fn drop(&mut self) {
+        let wakeup = Semaphore::release_locked(&mut self.semaphore.state.lock());
+        if let Some(wakeup) = wakeup {
+            wakeup.wake();
+        }
+    }
+}
+
+struct BarrierState {
+    count: usize,
+    waiting: VecDeque<Arc<Signal>>,
+}
+
+/// A barrier
+///
+/// Blocks coroutines (threads outside of coroutines are parked) until `n`
+/// of them are waiting, then releases them all. Can be reused.
+pub struct Barrier {
+    n: usize,
+    state: spin::Mutex<BarrierState>,
+}
+
+/// Returned by `Barrier::wait()`
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// Is this the coroutine that released the others?
+    ///
+    /// Exactly one coroutine of every group is the leader.
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+impl Barrier {
+    /// Create a barrier for groups of `n` coroutines.
+    pub fn new(n: usize) -> Self {
+        Barrier {
+            n: n,
+            state: spin::Mutex::new(BarrierState {
+                count: 0,
+                waiting: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Block until `n` coroutines are waiting.
+    pub fn wait(&self) -> BarrierWaitResult {
+        match self.arrive() {
+            Some(signal) => {
+                Signal::wait(&signal, None);
+                BarrierWaitResult(false)
+            }
+            None => BarrierWaitResult(true),
+        }
+    }
+
+    /// Like `wait()`, but gives up after `timeout_ms`.
+    ///
+    /// Returns `None` on timeout; the coroutine is not counted as waiting
+    /// anymore.
+    pub fn wait_timeout(&self, timeout_ms: i64) -> Option<BarrierWaitResult> {
+        let deadline = deadline_in(timeout_ms);
+        let signal = match self.arrive() {
+            Some(signal) => signal,
+            None => return Some(BarrierWaitResult(true)),
+        };
+
+        if !Signal::wait(&signal, Some(deadline)) {
+            let mut state = self.state.lock();
+            if !signal.is_signaled() {
+                remove_signal(&mut state.waiting, &signal);
+                state.count -= 1;
+                return None;
+            }
+        }
+        Some(BarrierWaitResult(false))
+    }
+
+    /// Count in a waiting coroutine; release everyone if it completes the
+    /// group.
+    fn arrive(&self) -> Option<Arc<Signal>> {
+        let mut wakeups = Vec::new();
+        {
+            let mut state = self.state.lock();
+            state.count += 1;
+            if state.count < self.n {
+                let signal = Signal::new();
+                state.waiting.push_back(signal.clone());
+                return Some(signal);
+            }
+
+            state.count = 0;
+            while let Some(signal) = state.waiting.pop_front() {
+                wakeups.push(signal.signal());
+            }
+        }
+        wake_all(wakeups);
+        None
+    }
+}
+
+struct WaitGroupState {
+    count: usize,
+    waiting: VecDeque<Arc<Signal>>,
+}
+
+/// A wait group
+///
+/// Counts pending tasks: `add()` before starting them, `done()` when each
+/// finishes, and `wait()` blocks until there are none left.
+pub struct WaitGroup {
+    state: spin::Mutex<WaitGroupState>,
+}
+
+impl WaitGroup {
+    /// Create a wait group with no pending tasks.
+    pub fn new() -> Self {
+        WaitGroup {
+            state: spin::Mutex::new(WaitGroupState {
+                count: 0,
+                waiting: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Add `n` pending tasks.
+    pub fn add(&self, n: usize) {
+        self.state.lock().count += n;
+    }
+
+    /// Mark one pending task as finished.
+    ///
+    /// Panics if there are no pending tasks.
+    pub fn done(&self) {
+        let mut wakeups = Vec::new();
+        {
+            let mut state = self.state.lock();
+            assert!(state.count > 0, "WaitGroup::done() called too many times");
+            state.count -= 1;
+            if state.count == 0 {
+                while let Some(signal) = state.waiting.pop_front() {
+                    wakeups.push(signal.signal());
                 }
             }
         }
+        wake_all(wakeups);
     }
 
-    /// Attempt to acquire this lock.
-    pub fn try_lock(&self) -> ssync::TryLockResult<ssync::MutexGuard<T>> {
-        self.lock.try_lock()
+    /// Number of pending tasks.
+    pub fn count(&self) -> usize {
+        self.state.lock().count
+    }
+
+    /// Block until there are no pending tasks.
+    pub fn wait(&self) {
+        if let Some(signal) = self.wait_prepare() {
+            Signal::wait(&signal, None);
+        }
+    }
+
+    /// Like `wait()`, but gives up after `timeout_ms`.
+    ///
+    /// Returns `false` on timeout.
+    pub fn wait_timeout(&self, timeout_ms: i64) -> bool {
+        let deadline = deadline_in(timeout_ms);
+        let signal = match self.wait_prepare() {
+            Some(signal) => signal,
+            None => return true,
+        };
+
+        if !Signal::wait(&signal, Some(deadline)) {
+            let mut state = self.state.lock();
+            if !signal.is_signaled() {
+                remove_signal(&mut state.waiting, &signal);
+                return false;
+            }
+        }
+        true
+    }
+
+    fn wait_prepare(&self) -> Option<Arc<Signal>> {
+        let mut state = self.state.lock();
+        if state.count == 0 {
+            return None;
+        }
+
+        let signal = Signal::new();
+        state.waiting.push_back(signal.clone());
+        Some(signal)
     }
 }
@@ -1,5 +1,9 @@
 use super::RW;
 use super::evented::{Evented, EventedImpl, MioAdapter};
+#[cfg(feature = "sim")]
+use super::evented::{Backend, sim_unsupported};
+#[cfg(feature = "sim")]
+use super::sim::{self, SimListener, SimStream};
 use std::io;
 use std::net::SocketAddr;
 use super::mio_orig;
@@ -8,8 +12,10 @@ use std;
 pub use mio_orig::tcp::Shutdown;
 
 /// TCP Listener
+#[cfg(not(feature = "sim"))]
 pub type TcpListener = MioAdapter<mio_orig::tcp::TcpListener>;
 
+#[cfg(not(feature = "sim"))]
 impl TcpListener {
     /// Local address
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
@@ -27,6 +33,7 @@ impl TcpListener {
     }
 }
 
+#[cfg(not(feature = "sim"))]
 impl TcpListener {
     /// Bind to a port
     pub fn bind(addr: &SocketAddr) -> io::Result<Self> {
@@ -41,8 +48,10 @@ impl TcpListener {
 }
 
 /// TCP Stream
+#[cfg(not(feature = "sim"))]
 pub type TcpStream = MioAdapter<mio_orig::tcp::TcpStream>;
 
+#[cfg(not(feature = "sim"))]
 impl TcpStream {
     /// Create a new TCP stream an issue a non-blocking connect to the specified address.
     pub fn connect(addr: &SocketAddr) -> io::Result<Self> {
@@ -98,3 +107,143 @@ impl TcpStream {
         self.shared().io_ref().try_clone().map(|t| MioAdapter::new(t))
     }
 }
+
+/// TCP Listener
+///
+/// Simulated when created by a coroutine placed on a simulated host (see
+/// `sim`).
+#[cfg(feature = "sim")]
+pub type TcpListener = MioAdapter<Backend<mio_orig::tcp::TcpListener, SimListener>>;
+
+#[cfg(feature = "sim")]
+impl TcpListener {
+    /// Local address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.local_addr(),
+            Backend::Sim(ref io) => io.local_addr(),
+        }
+    }
+
+    /// TODO: document
+    pub fn take_socket_error(&self) -> io::Result<()> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.take_socket_error(),
+            Backend::Sim(_) => Ok(()),
+        }
+    }
+
+    /// Try cloning the listener descriptor.
+    pub fn try_clone(&self) -> io::Result<TcpListener> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.try_clone().map(|t| MioAdapter::new(Backend::Mio(t))),
+            Backend::Sim(_) => Err(sim_unsupported()),
+        }
+    }
+}
+
+#[cfg(feature = "sim")]
+impl TcpListener {
+    /// Bind to a port
+    pub fn bind(addr: &SocketAddr) -> io::Result<Self> {
+        match sim::current_host() {
+            Some(host) => host.tcp_listen(addr).map(|t| MioAdapter::new(Backend::Sim(t))),
+            None => {
+                mio_orig::tcp::TcpListener::bind(addr).map(|t| MioAdapter::new(Backend::Mio(t)))
+            }
+        }
+    }
+
+    /// Creates a new TcpListener from an instance of a `std::net::TcpListener` type.
+    pub fn from_listener(listener: std::net::TcpListener, addr: &SocketAddr) -> io::Result<Self> {
+        mio_orig::tcp::TcpListener::from_listener(listener, addr)
+            .map(|t| MioAdapter::new(Backend::Mio(t)))
+    }
+}
+
+/// TCP Stream
+///
+/// Simulated when created by a coroutine placed on a simulated host (see
+/// `sim`), or accepted from a simulated `TcpListener`.
+#[cfg(feature = "sim")]
+pub type TcpStream = MioAdapter<Backend<mio_orig::tcp::TcpStream, SimStream>>;
+
+#[cfg(feature = "sim")]
+impl TcpStream {
+    /// Create a new TCP stream an issue a non-blocking connect to the specified address.
+    pub fn connect(addr: &SocketAddr) -> io::Result<Self> {
+        let io = match sim::current_host() {
+            Some(host) => Backend::Sim(try!(host.tcp_connect(addr))),
+            None => Backend::Mio(try!(mio_orig::tcp::TcpStream::connect(addr))),
+        };
+        let stream = MioAdapter::new(io);
+        stream.block_on(RW::write());
+        Ok(stream)
+    }
+
+    /// Creates a new TcpStream from the pending socket inside the given
+    /// `std::net::TcpBuilder`, connecting it to the address specified.
+    pub fn connect_stream(stream: std::net::TcpStream, addr: &SocketAddr) -> io::Result<Self> {
+        mio_orig::tcp::TcpStream::connect_stream(stream, addr).map(|t| {
+            let stream = MioAdapter::new(Backend::Mio(t));
+            stream.block_on(RW::write());
+            stream
+        })
+    }
+
+    /// Local address of connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.local_addr(),
+            Backend::Sim(ref io) => io.local_addr(),
+        }
+    }
+
+    /// Peer address of connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.peer_addr(),
+            Backend::Sim(ref io) => io.peer_addr(),
+        }
+    }
+
+    /// Shutdown the connection.
+    pub fn shutdown(&self, how: mio_orig::tcp::Shutdown) -> io::Result<()> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.shutdown(how),
+            Backend::Sim(ref io) => io.shutdown(how),
+        }
+    }
+
+    /// Set `no_delay`.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.set_nodelay(nodelay),
+            Backend::Sim(_) => Ok(()),
+        }
+    }
+
+    /// Set keepalive.
+    pub fn set_keepalive(&self, seconds: Option<u32>) -> io::Result<()> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.set_keepalive(seconds),
+            Backend::Sim(_) => Ok(()),
+        }
+    }
+
+    /// TODO: document
+    pub fn take_socket_error(&self) -> io::Result<()> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.take_socket_error(),
+            Backend::Sim(_) => Ok(()),
+        }
+    }
+
+    /// Try cloning the socket descriptor.
+    pub fn try_clone(&self) -> io::Result<TcpStream> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.try_clone().map(|t| MioAdapter::new(Backend::Mio(t))),
+            Backend::Sim(_) => Err(sim_unsupported()),
+        }
+    }
+}
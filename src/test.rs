@@ -0,0 +1,154 @@
+use super::{Config, Mioco, Scheduler, SchedulerThread, CoroutineControl};
+use super::{sender_retry};
+use super::thread::{Handler, Message};
+use super::mio_orig::EventLoop;
+use super::rng::XorShift;
+use super::timer::VirtualClock;
+
+use std::env;
+use std::io;
+use std::mem;
+use time;
+
+/// Deterministic, single-threaded mioco runtime for tests
+///
+/// All coroutines run on one thread and, whenever more than one of them
+/// is ready, the order in which they are resumed is picked by a PRNG
+/// seeded with `seed`. Running the same test with the same seed replays
+/// the same interleaving, and running it with many seeds explores
+/// different ones.
+///
+/// Panics in coroutines are not caught, so they fail the test.
+///
+/// Timers are driven by a `VirtualClock`: when no coroutine is ready,
+/// time jumps straight to the nearest timer deadline, so timeouts of any
+/// length expire immediately and in a deterministic order. The clock can
+/// also be moved explicitly using `clock()`.
+///
+/// Note: readiness of real IO (sockets, pipes) still depends on the OS,
+/// and virtual time does not wait for it.
+pub struct Runtime {
+    seed: u64,
+    clock: VirtualClock,
+}
+
+impl Runtime {
+    /// Create a runtime using a given `seed`.
+    pub fn new(seed: u64) -> Self {
+        Runtime {
+            seed: seed,
+            clock: VirtualClock::new(),
+        }
+    }
+
+    /// Create a runtime seeded from `MIOCO_TEST_SEED` environment variable.
+    ///
+    /// If it is not set, a seed is generated and logged (at `info` level),
+    /// so a failing run can be replayed. Use `seed()` to report it
+    /// otherwise.
+    pub fn from_env() -> Self {
+        let seed = match env::var("MIOCO_TEST_SEED").ok().and_then(|s| s.parse().ok()) {
+            Some(seed) => seed,
+            None => {
+                let seed = time::precise_time_ns();
+                info!("mioco::test::Runtime: MIOCO_TEST_SEED={}", seed);
+                seed
+            }
+        };
+        Runtime::new(seed)
+    }
+
+    /// Seed of this runtime.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Virtual clock driving timers of this runtime.
+    pub fn clock(&self) -> VirtualClock {
+        self.clock.clone()
+    }
+
+    /// Run `f` as a first coroutine and return when all coroutines finish.
+    pub fn run<F>(&self, f: F)
+        where F: FnOnce() -> io::Result<()> + Send + 'static
+    {
+        let mut config = Config::new();
+        config.set_thread_num(1);
+        config.set_catch_panics(false);
+        config.set_virtual_clock(self.clock.clone());
+        config.set_scheduler(Box::new(SeededScheduler {
+            seed: self.seed,
+            clock: self.clock.clone(),
+        }));
+
+        Mioco::new_configured(config).start(f);
+    }
+}
+
+/// Scheduler resuming ready coroutines in a seeded random order
+struct SeededScheduler {
+    seed: u64,
+    clock: VirtualClock,
+}
+
+impl Scheduler for SeededScheduler {
+    fn spawn_thread(&self) -> Box<SchedulerThread> {
+        Box::new(SeededSchedulerThread {
+            rng: XorShift::new(self.seed),
+            pending: Vec::new(),
+            clock: self.clock.clone(),
+            idle: false,
+        })
+    }
+}
+
+struct SeededSchedulerThread {
+    rng: XorShift,
+    pending: Vec<CoroutineControl>,
+    clock: VirtualClock,
+    /// Nothing was ready during last `tick()`
+    idle: bool,
+}
+
+impl SeededSchedulerThread {
+    fn push(&mut self, event_loop: &mut EventLoop<Handler>, coroutine_ctrl: CoroutineControl) {
+        if self.pending.is_empty() {
+            // Make sure `tick()` is not delayed by waiting for IO events
+            sender_retry(&event_loop.channel(), Message::Tick);
+        }
+        self.idle = false;
+        self.pending.push(coroutine_ctrl);
+    }
+}
+
+impl SchedulerThread for SeededSchedulerThread {
+    fn spawned(&mut self, event_loop: &mut EventLoop<Handler>, coroutine_ctrl: CoroutineControl) {
+        self.push(event_loop, coroutine_ctrl);
+    }
+
+    fn ready(&mut self, event_loop: &mut EventLoop<Handler>, coroutine_ctrl: CoroutineControl) {
+        self.push(event_loop, coroutine_ctrl);
+    }
+
+    fn tick(&mut self, event_loop: &mut EventLoop<Handler>) {
+        let mut pending = mem::replace(&mut self.pending, Vec::new());
+        while !pending.is_empty() {
+            let i = self.rng.below(pending.len());
+            pending.swap_remove(i).resume(event_loop);
+        }
+
+        if !self.pending.is_empty() || !self.clock.has_timers() {
+            return;
+        }
+
+        // Everything is blocked. Give already queued events one more
+        // round to make something ready, and only then advance time.
+        if self.idle {
+            self.idle = false;
+            self.clock.advance_to_next();
+        } else {
+            self.idle = true;
+            sender_retry(&event_loop.channel(), Message::Tick);
+        }
+    }
+}
@@ -399,7 +399,7 @@ fn exit_notifier_simple() {
 
             let notify = notify;
 
-            assert!(!notify.read().is_panic());
+            assert!(!notify.read().unwrap().is_panic());
 
             let mut lock = finished_copy.lock().unwrap();
             *lock = true;
@@ -422,7 +422,7 @@ fn exit_notifier_simple_panic() {
 
             let notify = notify;
 
-            assert!(notify.read().is_panic());
+            assert!(notify.read().unwrap().is_panic());
 
             let mut lock = finished_copy.lock().unwrap();
             *lock = true;
@@ -448,18 +448,18 @@ fn exit_notifier_wrap_after_finish() {
 
             let handle2 = mioco::spawn_ext(move || {
                 let notify1 = notify1;
-                assert!(notify1.read().is_panic());
+                assert!(notify1.read().unwrap().is_panic());
                 Ok(())
             });
 
             let notify2 = handle2.exit_notificator();
             let notify2 = notify2;
-            assert!(!notify2.read().is_panic());
+            assert!(!notify2.read().unwrap().is_panic());
 
 
             let notify1 = handle1.exit_notificator();
             let notify1 = notify1;
-            assert!(notify1.read().is_panic());
+            assert!(notify1.read().unwrap().is_panic());
 
 
             let mut lock = finished_copy.lock().unwrap();
@@ -845,7 +845,7 @@ fn tcp_basic_client_server() {
                 let addr = FromStr::from_str("127.0.0.1:0").unwrap();
                 let listener = mioco::tcp::TcpListener::bind(&addr).unwrap();
 
-                out.send(listener.local_addr().unwrap());
+                out.send(listener.local_addr().unwrap()).unwrap();
 
                 for i in 0..2 {
                     let mut conn = listener.accept().unwrap();
@@ -861,7 +861,7 @@ fn tcp_basic_client_server() {
             });
 
             mioco::spawn(move || {
-                let addr = inn.read();
+                let addr = inn.read().unwrap();
 
                 let stream = mioco::tcp::TcpStream::connect(&addr).unwrap();
                 stream.try_write(b"Hello world").unwrap().unwrap();
@@ -1035,3 +1035,1163 @@ fn in_coroutine_true() {
 fn in_coroutine_false() {
     assert!(!mioco::in_coroutine());
 }
+
+#[test]
+fn tracer_lifecycle() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingTracer {
+        spawned: Arc<AtomicUsize>,
+        blocked: Arc<AtomicUsize>,
+        finished: Arc<AtomicUsize>,
+    }
+
+    impl mioco::trace::Tracer for CountingTracer {
+        fn spawned(&self, _info: mioco::trace::Info) {
+            self.spawned.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn blocked(&self, _info: mioco::trace::Info, on: &[(mioco::EventSourceId, mioco::RW)]) {
+            assert_eq!(on.len(), 1);
+            self.blocked.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn finished(&self, _info: mioco::trace::Info, status: &mioco::ExitStatus) {
+            assert!(!status.is_panic());
+            self.finished.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    for &threads in THREADS_N.iter() {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let blocked = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::new(AtomicUsize::new(0));
+
+        let mut config = mioco::Config::new();
+        config.set_thread_num(threads);
+        config.set_tracer(Box::new(CountingTracer {
+            spawned: spawned.clone(),
+            blocked: blocked.clone(),
+            finished: finished.clone(),
+        }));
+
+        mioco::Mioco::new_configured(config).start(|| {
+            for _ in 0..16 {
+                mioco::spawn(|| {
+                    mioco::sleep(10);
+                    Ok(())
+                });
+            }
+            Ok(())
+        });
+
+        assert_eq!(spawned.load(Ordering::SeqCst), 17);
+        assert!(blocked.load(Ordering::SeqCst) >= 16);
+        assert_eq!(finished.load(Ordering::SeqCst), 17);
+    }
+}
+
+#[test]
+fn chrome_tracer_records_spans() {
+    for &threads in THREADS_N.iter() {
+        let tracer = mioco::trace::ChromeTracer::new(1024);
+
+        let mut config = mioco::Config::new();
+        config.set_thread_num(threads);
+        config.set_tracer(Box::new(tracer.clone()));
+
+        mioco::Mioco::new_configured(config).start(|| {
+            for _ in 0..4 {
+                mioco::spawn(|| {
+                    mioco::sleep(10);
+                    Ok(())
+                });
+            }
+            Ok(())
+        });
+
+        let mut out = Vec::new();
+        tracer.write_json(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("{\"traceEvents\":["));
+        assert!(out.ends_with("]}"));
+        assert!(out.contains("\"ph\":\"X\""));
+        assert!(out.contains("\"name\":\"blocked\""));
+    }
+}
+
+#[test]
+fn current_coroutine_info() {
+    assert!(mioco::current().is_none());
+
+    for &threads in THREADS_N.iter() {
+        mioco::start_threads(threads, || {
+            let parent = mioco::current().unwrap();
+            assert!(parent.name.is_none());
+
+            mioco::set_name("parent");
+            let parent = mioco::current().unwrap();
+            assert_eq!(parent.name.as_ref().unwrap().as_str(), "parent");
+            assert!(format!("{}", parent).contains("parent"));
+
+            mioco::spawn(move || {
+                let child = mioco::current().unwrap();
+                assert!(child.name.is_none());
+                assert!(child.id != parent.id);
+                Ok(())
+            });
+            Ok(())
+        })
+    }
+}
+
+#[test]
+fn run_time_and_sched_latency() {
+    for &threads in THREADS_N.iter() {
+        let mut mioco = mioco::Mioco::new_configured({
+            let mut config = mioco::Config::new();
+            config.set_thread_num(threads);
+            config.set_timing_stats(true);
+            config
+        });
+
+        mioco.start(|| {
+            let handle = mioco::spawn_ext(|| {
+                let starting_time = SteadyTime::now();
+                while SteadyTime::now() - starting_time < Duration::milliseconds(50) {
+                }
+                Ok(())
+            });
+
+            let notify = handle.exit_notificator();
+            assert!(!notify.read().unwrap().is_panic());
+
+            assert!(handle.run_time() >= Duration::milliseconds(50));
+            assert!(handle.sched_latency() >= Duration::zero());
+            assert!(mioco::stats().run_time().count() >= 1);
+            Ok(())
+        });
+
+        let stats = mioco.stats();
+        assert_eq!(stats.run_time().count(), 2);
+        assert!(stats.sched_latency().count() >= 2);
+        assert!(stats.run_time().percentile(100.0).unwrap() >= 50 * 1000);
+    }
+}
+
+#[test]
+fn stack_usage_tracking() {
+    fn recurse(depth: usize) -> usize {
+        let buf = [depth as u8; 1024];
+        if depth == 0 {
+            buf[0] as usize
+        } else {
+            recurse(depth - 1) + buf[depth % 1024] as usize
+        }
+    }
+
+    for &threads in THREADS_N.iter() {
+        let mut config = mioco::Config::new();
+        config.set_thread_num(threads);
+        config.set_stack_usage_tracking(true);
+
+        let mut mioco = mioco::Mioco::new_configured(config);
+
+        mioco.start(|| {
+            let shallow = mioco::spawn_ext(|| Ok(()));
+            let deep = mioco::spawn_ext(|| {
+                mioco::set_name("deep");
+                recurse(256);
+                Ok(())
+            });
+
+            let shallow_notify = shallow.exit_notificator();
+            let deep_notify = deep.exit_notificator();
+            shallow_notify.read().unwrap();
+            deep_notify.read().unwrap();
+
+            assert!(deep.stack_usage().unwrap() >= 256 * 1024);
+            assert!(shallow.stack_usage().unwrap() < deep.stack_usage().unwrap());
+            Ok(())
+        });
+
+        let stack_usage = mioco.stats().stack_usage();
+        let &(_, ref deep) = stack_usage.iter().find(|&&(ref name, _)| name == "deep").unwrap();
+        assert_eq!(deep.count(), 1);
+    }
+}
+
+#[test]
+fn stack_overflow_guard_range_and_message() {
+    use super::overflow;
+
+    let page = overflow::page_size();
+    let (start, end) = overflow::guard_range(16 * page);
+    assert_eq!((start, end), (16 * page, 17 * page));
+    assert!(overflow::in_guard(start, start, end));
+    assert!(overflow::in_guard(end - 1, start, end));
+    assert!(!overflow::in_guard(start - 1, start, end));
+    assert!(!overflow::in_guard(end, start, end));
+    assert!(!overflow::in_guard(0, 0, 0));
+
+    let msg = overflow::overflow_message(!0, Some("deep"), 2 * 1024 * 1024);
+    let expected = format!("mioco: coroutine {} (deep) overflowed its 2048 KiB stack\n", !0usize);
+    assert_eq!(msg.as_bytes(), expected.as_bytes());
+    let msg = overflow::overflow_message(0, None, 64 * 1024);
+    assert_eq!(msg.as_bytes(), &b"mioco: coroutine 0 overflowed its 64 KiB stack\n"[..]);
+
+    let long = ::std::iter::repeat("x").take(1024).collect::<String>();
+    let msg = overflow::overflow_message(1, Some(&long), 0);
+    assert_eq!(msg.as_bytes().len(), 512);
+}
+
+#[test]
+fn stack_overflow_alt_stack_install_uninstall() {
+    use super::overflow;
+
+    thread::spawn(|| {
+        let had_alt_stack = overflow::alt_stack_enabled();
+        {
+            let _alt_stack = overflow::AltStack::new();
+            if cfg!(all(target_os = "linux",
+                        any(target_arch = "x86", target_arch = "x86_64",
+                            target_arch = "arm", target_arch = "aarch64"))) {
+                assert!(overflow::alt_stack_enabled());
+            }
+        }
+        // Left as it was: disabled if `AltStack` installed its own, kept
+        // if the thread already had one.
+        assert_eq!(overflow::alt_stack_enabled(), had_alt_stack);
+    })
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn test_runtime_is_deterministic() {
+    fn run_order(seed: u64) -> Vec<usize> {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let order_copy = order.clone();
+
+        mioco::test::Runtime::new(seed).run(move || {
+            for i in 0..8 {
+                let order = order_copy.clone();
+                mioco::spawn(move || {
+                    for _ in 0..4 {
+                        order.lock().unwrap().push(i);
+                        mioco::yield_now();
+                    }
+                    Ok(())
+                });
+            }
+            Ok(())
+        });
+
+        let order = order.lock().unwrap().clone();
+        assert_eq!(order.len(), 8 * 4);
+        order
+    }
+
+    let first = run_order(42);
+    assert_eq!(first, run_order(42));
+    assert!((0..16).map(run_order).any(|order| order != first));
+}
+
+#[test]
+fn test_runtime_virtual_clock() {
+    let woken = Arc::new(Mutex::new(Vec::new()));
+    let woken_copy = woken.clone();
+
+    let start = SteadyTime::now();
+    mioco::test::Runtime::new(7).run(move || {
+        let virtual_start = mioco::timer::now();
+        for &delay in [3600 * 1000, 10, 60 * 1000].iter() {
+            let woken = woken_copy.clone();
+            mioco::spawn(move || {
+                mioco::sleep(delay);
+                woken.lock().unwrap().push((delay, mioco::timer::now() - virtual_start));
+                Ok(())
+            });
+        }
+        Ok(())
+    });
+    assert!(SteadyTime::now() - start < Duration::seconds(10));
+
+    let woken = woken.lock().unwrap();
+    assert_eq!(woken.iter().map(|&(delay, _)| delay).collect::<Vec<_>>(),
+               vec![10, 60 * 1000, 3600 * 1000]);
+    for &(delay, elapsed) in woken.iter() {
+        assert_eq!(elapsed, Duration::milliseconds(delay));
+    }
+}
+
+#[test]
+fn virtual_clock_explicit_advance() {
+    let clock = mioco::timer::VirtualClock::new();
+    let clock_copy = clock.clone();
+    let fired = Arc::new(Mutex::new(false));
+    let fired_copy = fired.clone();
+
+    let mut config = mioco::Config::new();
+    config.set_thread_num(1);
+    config.set_virtual_clock(clock.clone());
+    mioco::Mioco::new_configured(config).start(move || {
+        let fired = fired_copy.clone();
+        mioco::spawn(move || {
+            mioco::sleep(5000);
+            *fired.lock().unwrap() = true;
+            Ok(())
+        });
+
+        let clock = clock_copy.clone();
+        thread::spawn(move || {
+            while !clock.has_timers() {
+                thread::sleep(std::time::Duration::from_millis(1));
+            }
+            clock.advance(4999);
+            assert!(clock.has_timers());
+            clock.advance(1);
+        });
+        Ok(())
+    });
+
+    assert!(*fired.lock().unwrap());
+}
+
+#[test]
+#[cfg(feature = "sim")]
+fn sim_network_tcp() {
+    let net = mioco::sim::SimNetwork::new(1);
+    let mut link = mioco::sim::Link::new();
+    link.set_latency(50);
+    net.set_default_link(link);
+
+    let server = net.host("10.0.0.1".parse().unwrap());
+    let client = net.host("10.0.0.2".parse().unwrap());
+
+    let reset = Arc::new(Mutex::new(false));
+    let reset_copy = reset.clone();
+
+    mioco::test::Runtime::new(1).run(move || {
+        mioco::sim::set_current_host(Some(server.clone()));
+        let listener = try!(mioco::tcp::TcpListener::bind(&"0.0.0.0:7".parse().unwrap()));
+        let addr = try!(listener.local_addr());
+        assert_eq!(addr, "10.0.0.1:7".parse().unwrap());
+
+        mioco::spawn(move || {
+            let mut conn = try!(listener.accept());
+            let mut buf = [0u8; 1024];
+            loop {
+                let size = try!(conn.read(&mut buf));
+                if size == 0 {
+                    return Ok(());
+                }
+                try!(conn.write_all(&buf[0..size]));
+            }
+        });
+
+        mioco::sim::set_current_host(Some(client.clone()));
+        let start = mioco::timer::now();
+        let mut conn = try!(mioco::tcp::TcpStream::connect(&addr));
+        assert_eq!(try!(conn.peer_addr()), addr);
+        try!(conn.write_all(b"ping"));
+        let mut buf = [0u8; 4];
+        try!(conn.read_exact(&mut buf));
+        assert_eq!(&buf, b"ping");
+        assert_eq!(mioco::timer::now() - start, Duration::milliseconds(100));
+
+        net.partition(server.ip(), client.ip());
+        let err = conn.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        assert!(mioco::tcp::TcpStream::connect(&addr).is_err());
+        *reset_copy.lock().unwrap() = true;
+        Ok(())
+    });
+
+    assert!(*reset.lock().unwrap());
+}
+
+#[test]
+#[cfg(feature = "sim")]
+fn sim_network_udp() {
+    let net = mioco::sim::SimNetwork::new(1);
+    let a = net.host("10.0.0.1".parse().unwrap());
+    let b = net.host("10.0.0.2".parse().unwrap());
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_copy = received.clone();
+
+    let mut config = mioco::Config::new();
+    config.set_thread_num(1);
+    config.set_sim_host(a.clone());
+    mioco::Mioco::new_configured(config).start(move || {
+        let mut sender = try!(mioco::udp::UdpSocket::v4());
+
+        mioco::sim::set_current_host(Some(b.clone()));
+        let mut receiver = try!(mioco::udp::UdpSocket::bound(&"0.0.0.0:53".parse().unwrap()));
+        let target = try!(receiver.local_addr());
+        assert_eq!(target, "10.0.0.2:53".parse().unwrap());
+
+        net.partition(a.ip(), b.ip());
+        try!(sender.write(b"lost", &target));
+        net.heal_all();
+        try!(sender.write(b"delivered", &target));
+
+        let mut buf = [0u8; 16];
+        let (size, from) = try!(receiver.read(&mut buf));
+        assert_eq!(from, try!(sender.local_addr()));
+        received_copy.lock().unwrap().push(buf[..size].to_vec());
+        Ok(())
+    });
+
+    assert_eq!(*received.lock().unwrap(), vec![b"delivered".to_vec()]);
+}
+
+#[test]
+fn fault_injection_preserves_data() {
+    let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+    let received = Arc::new(Mutex::new(Vec::new()));
+
+    let mut faults = mioco::fault::Faults::new(3);
+    faults.set_short_read(0.5)
+          .set_partial_write(0.5)
+          .set_would_block(0.1)
+          .set_interrupted(0.1);
+
+    let mut config = mioco::Config::new();
+    config.set_thread_num(1);
+    config.set_faults(faults);
+
+    let data_copy = data.clone();
+    let received_copy = received.clone();
+    mioco::Mioco::new_configured(config).start(move || {
+        let (mut reader, mut writer) = try!(mioco::unix::pipe());
+
+        mioco::spawn(move || {
+            try!(writer.write_all(&data_copy));
+            Ok(())
+        });
+
+        let mut buf = Vec::new();
+        try!(reader.read_to_end(&mut buf));
+        *received_copy.lock().unwrap() = buf;
+        Ok(())
+    });
+
+    assert!(*received.lock().unwrap() == data);
+}
+
+#[test]
+fn mem_duplex() {
+    for &threads in THREADS_N.iter() {
+        let data: Vec<u8> = (0..10 * 1024).map(|i| (i % 253) as u8).collect();
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let data_copy = data.clone();
+        let received_copy = received.clone();
+        mioco::start_threads(threads, move || {
+            let (mut a, mut b) = mioco::mem::duplex(16);
+
+            mioco::spawn(move || {
+                try!(a.write_all(&data_copy));
+                try!(a.shutdown(mioco::tcp::Shutdown::Write));
+
+                let mut reply = [0u8; 4];
+                try!(a.read_exact(&mut reply));
+                assert_eq!(&reply, b"done");
+                Ok(())
+            });
+
+            let mut buf = Vec::new();
+            try!(b.read_to_end(&mut buf));
+            *received_copy.lock().unwrap() = buf;
+            try!(b.write_all(b"done"));
+            Ok(())
+        });
+
+        assert!(*received.lock().unwrap() == data);
+    }
+}
+
+#[test]
+fn bounded_mailbox_backpressure() {
+    for &threads in THREADS_N.iter() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let received_copy = received.clone();
+        mioco::start_threads(threads, move || {
+            let (mail_send, mail_recv) = mioco::mail::bounded::<usize>(2);
+
+            assert_eq!(mail_send.try_send(0), Ok(()));
+            mail_send.send(1).unwrap();
+            assert_eq!(mail_send.try_send(2), Err(mioco::mail::TrySendError::Full(2)));
+
+            let coroutine_send = mail_send.clone();
+            mioco::spawn(move || {
+                for i in 2..50 {
+                    coroutine_send.send(i).unwrap();
+                }
+                Ok(())
+            });
+
+            let thread_send = mail_send.clone();
+            thread::spawn(move || {
+                for i in 50..100 {
+                    thread_send.send(i).unwrap();
+                }
+            });
+
+            for _ in 0..100 {
+                let i = mail_recv.read().unwrap();
+                received_copy.lock().unwrap().push(i);
+                mioco::yield_now();
+            }
+            Ok(())
+        });
+
+        let mut received = received.lock().unwrap().clone();
+        assert!(received[..2] == [0, 1]);
+        received.sort();
+        assert!(received == (0..100).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn mailbox_disconnection() {
+    for &threads in THREADS_N.iter() {
+        let finished_ok = Arc::new(Mutex::new(false));
+
+        let finished_copy = finished_ok.clone();
+        mioco::start_threads(threads, move || {
+            let (mail_send, mail_recv) = mioco::mail::mailbox::<usize>();
+
+            for i in 0..4 {
+                let mail_send = mail_send.clone();
+                mioco::spawn(move || {
+                    mioco::yield_now();
+                    mail_send.send(i).unwrap();
+                    Ok(())
+                });
+            }
+            drop(mail_send);
+
+            let mut sum = 0;
+            while let Ok(i) = mail_recv.read() {
+                sum += i;
+            }
+            assert_eq!(sum, 0 + 1 + 2 + 3);
+            assert_eq!(mail_recv.try_read(), Err(mioco::mail::TryRecvError::Disconnected));
+
+            let (mail_send, mail_recv) = mioco::mail::bounded::<usize>(1);
+            drop(mail_recv);
+            assert_eq!(mail_send.send(1), Err(mioco::mail::SendError(1)));
+
+            *finished_copy.lock().unwrap() = true;
+            Ok(())
+        });
+
+        assert!(*finished_ok.lock().unwrap());
+    }
+}
+
+#[test]
+fn broadcast_mailbox() {
+    for &threads in THREADS_N.iter() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let received_copy = received.clone();
+        mioco::start_threads(threads, move || {
+            let sender = mioco::mail::broadcast::<usize>(8, mioco::mail::LagPolicy::DropOldest);
+
+            let mut notifications = Vec::new();
+            for _ in 0..3 {
+                let subscriber = sender.subscribe();
+                let received = received_copy.clone();
+                let handle = mioco::spawn_ext(move || {
+                    let mut sum = 0;
+                    while let Ok(i) = subscriber.read() {
+                        sum += i;
+                    }
+                    received.lock().unwrap().push(sum);
+                    Ok(())
+                });
+                notifications.push(handle.exit_notificator());
+            }
+
+            for i in 0..6 {
+                assert_eq!(sender.send(i), 3);
+            }
+            drop(sender);
+
+            let sender = mioco::mail::broadcast::<usize>(4, mioco::mail::LagPolicy::Report);
+            let lagging = sender.subscribe();
+            assert_eq!(sender.subscribers(), 1);
+            for i in 0..6 {
+                sender.send(i);
+            }
+            assert_eq!(lagging.read(), Err(mioco::mail::BroadcastRecvError::Lagged(2)));
+            assert_eq!(lagging.read(), Ok(2));
+            drop(lagging);
+            assert_eq!(sender.subscribers(), 0);
+
+            for notification in notifications {
+                let _ = notification.read();
+            }
+            Ok(())
+        });
+
+        assert_eq!(*received.lock().unwrap(), vec![15, 15, 15]);
+    }
+}
+
+#[test]
+fn oneshot_and_requester() {
+    for &threads in THREADS_N.iter() {
+        let finished_ok = Arc::new(Mutex::new(false));
+
+        let finished_copy = finished_ok.clone();
+        mioco::start_threads(threads, move || {
+            let (reply_send, reply_recv) = mioco::mail::oneshot::<usize>();
+            mioco::spawn(move || {
+                reply_send.send(42).unwrap();
+                Ok(())
+            });
+            assert_eq!(reply_recv.read(), Ok(42));
+
+            let (reply_send, reply_recv) = mioco::mail::oneshot::<usize>();
+            drop(reply_send);
+            assert_eq!(reply_recv.read(), Err(mioco::mail::RecvError));
+
+            let (requester, requests) = mioco::mail::requester::<usize, usize>();
+            mioco::spawn(move || {
+                while let Ok(request) = requests.read() {
+                    match request.request {
+                        0 => drop(request),
+                        1 => mioco::sleep(200),
+                        n => {
+                            let _ = request.reply(n * 2);
+                        }
+                    }
+                }
+                Ok(())
+            });
+
+            assert_eq!(requester.call(21), Ok(42));
+            assert_eq!(requester.call(0), Err(mioco::mail::CallError::Disconnected));
+            assert_eq!(requester.call_timeout(1, 10), Err(mioco::mail::CallError::TimedOut));
+            assert_eq!(requester.call_timeout(2, 10000), Ok(4));
+
+            *finished_copy.lock().unwrap() = true;
+            Ok(())
+        });
+
+        assert!(*finished_ok.lock().unwrap());
+    }
+}
+
+#[test]
+fn work_queue_distributes_messages() {
+    for &threads in THREADS_N.iter() {
+        let processed = Arc::new(Mutex::new(Vec::new()));
+
+        let processed_copy = processed.clone();
+        mioco::start_threads(threads, move || {
+            let (work_send, work_recv) = mioco::mail::work_queue::<usize>();
+
+            let mut notifications = Vec::new();
+            for worker in 0..4 {
+                let work_recv = work_recv.clone();
+                let processed = processed_copy.clone();
+                let handle = mioco::spawn_ext(move || {
+                    while let Ok(i) = work_recv.read() {
+                        processed.lock().unwrap().push((worker, i));
+                        mioco::yield_now();
+                    }
+                    Ok(())
+                });
+                notifications.push(handle.exit_notificator());
+            }
+            drop(work_recv);
+
+            for i in 0..100 {
+                work_send.send(i).unwrap();
+                if i % 10 == 0 {
+                    mioco::yield_now();
+                }
+            }
+            drop(work_send);
+
+            for notification in notifications {
+                let _ = notification.read();
+            }
+            Ok(())
+        });
+
+        let processed = processed.lock().unwrap();
+        let mut items: Vec<usize> = processed.iter().map(|&(_, i)| i).collect();
+        items.sort();
+        assert!(items == (0..100).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn work_queue_passes_on_unused_wakeup() {
+    let received = Arc::new(Mutex::new(None));
+
+    let received_copy = received.clone();
+    mioco::start_threads(1, move || {
+        let (work_send, work_recv) = mioco::mail::work_queue::<usize>();
+        let (other_send, other_recv) = mioco::mail::mailbox::<()>();
+        let (done_send, done_recv) = mioco::mail::mailbox::<()>();
+
+        // First in line: woken up for the item, but resumed by `other_recv`
+        let first_recv = work_recv.clone();
+        mioco::spawn(move || {
+            select!(
+                first_recv:r => panic!("resumed by the work queue"),
+                other_recv:r => {},
+            );
+            // Keep `first_recv` alive and unread
+            let _ = done_recv.read();
+            drop(first_recv);
+            Ok(())
+        });
+
+        let second_recv = work_recv.clone();
+        let handle = mioco::spawn_ext(move || {
+            *received_copy.lock().unwrap() = second_recv.read().ok();
+            done_send.send(()).unwrap();
+            Ok(())
+        });
+        drop(work_recv);
+
+        mioco::sleep(10);
+        other_send.send(()).unwrap();
+        work_send.send(1).unwrap();
+
+        let _ = handle.exit_notificator().read();
+        Ok(())
+    });
+
+    assert_eq!(*received.lock().unwrap(), Some(1));
+}
+
+#[test]
+fn mailbox_read_outside_coroutine() {
+    for &threads in THREADS_N.iter() {
+        let (mail_send, mail_recv) = mioco::mail::mailbox::<usize>();
+        let (timeout_send, timeout_recv) = mioco::mail::mailbox::<()>();
+
+        let join = thread::spawn(move || {
+            assert_eq!(timeout_recv.read_timeout(10),
+                       Err(mioco::mail::RecvTimeoutError::Timeout));
+            let mut sum = 0;
+            while let Ok(i) = mail_recv.read() {
+                sum += i;
+            }
+            drop(timeout_send);
+            sum
+        });
+
+        mioco::start_threads(threads, move || {
+            for i in 0..10 {
+                mail_send.send(i).unwrap();
+                mioco::sleep(1);
+            }
+            Ok(())
+        });
+
+        assert_eq!(join.join().unwrap(), 45);
+    }
+}
+
+#[test]
+fn std_channel_forward_and_notify() {
+    for &threads in THREADS_N.iter() {
+        let finished_ok = Arc::new(Mutex::new(false));
+
+        let finished_copy = finished_ok.clone();
+        mioco::start_threads(threads, move || {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let forwarded = mioco::mail::forward(rx);
+            let (notifier, notified) = mioco::mail::notify();
+
+            thread::spawn(move || {
+                for i in 0..3 {
+                    tx.send(i).unwrap();
+                }
+                notifier.notify();
+                notifier.notify();
+            });
+
+            let mut sum = 0;
+            let mut was_notified = false;
+            while !was_notified || sum < 3 {
+                select!(
+                    forwarded:r => {
+                        if let Ok(i) = forwarded.try_read() {
+                            sum += i;
+                        }
+                    },
+                    notified:r => {
+                        was_notified = notified.try_wait();
+                    },
+                );
+            }
+            assert_eq!(sum, 0 + 1 + 2);
+            assert_eq!(forwarded.read(), Err(mioco::mail::RecvError));
+            assert!(!notified.try_wait());
+
+            *finished_copy.lock().unwrap() = true;
+            Ok(())
+        });
+
+        assert!(*finished_ok.lock().unwrap());
+    }
+}
+
+#[test]
+fn mailbox_delayed_send() {
+    let finished_ok = Arc::new(Mutex::new(false));
+
+    let finished_copy = finished_ok.clone();
+    mioco::test::Runtime::new(3).run(move || {
+        let start = mioco::timer::now();
+        let (send, recv) = mioco::mail::mailbox();
+
+        let a = send.send_after(60 * 1000, "a");
+        let _ = send.send_after(10, "b");
+        let c = send.send_after(30 * 1000, "c");
+
+        assert_eq!(recv.read(), Ok("b"));
+        assert!(a.is_pending());
+        assert_eq!(c.cancel(), Some("c"));
+
+        assert_eq!(recv.read(), Ok("a"));
+        assert!(!a.is_pending());
+        assert_eq!(a.cancel(), None);
+        assert_eq!(mioco::timer::now() - start, Duration::milliseconds(60 * 1000));
+        assert_eq!(recv.try_read(), Err(mioco::mail::TryRecvError::Empty));
+
+        *finished_copy.lock().unwrap() = true;
+        Ok(())
+    });
+
+    assert!(*finished_ok.lock().unwrap());
+}
+
+#[test]
+fn mailbox_delayed_send_cancel_drops_delivery() {
+    let (send, recv) = mioco::mail::mailbox();
+    let delayed = send.send_after(60 * 1000, "late");
+    drop(send);
+
+    // The pending delivery keeps the mailbox connected
+    assert_eq!(recv.try_read(), Err(mioco::mail::TryRecvError::Empty));
+    assert_eq!(delayed.cancel(), Some("late"));
+    assert_eq!(recv.try_read(), Err(mioco::mail::TryRecvError::Disconnected));
+}
+
+#[test]
+fn priority_mailbox_order() {
+    for &threads in THREADS_N.iter() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let received_copy = received.clone();
+        mioco::start_threads(threads, move || {
+            let (send, recv) = mioco::mail::priority_mailbox();
+
+            for &(priority, msg) in [(0, "data1"), (0, "data2"), (10, "shutdown"), (5, "reload"),
+                                     (0, "data3")]
+                                        .iter() {
+                send.send(priority, msg).unwrap();
+            }
+
+            let late = send.clone();
+            drop(send);
+            mioco::spawn(move || {
+                mioco::sleep(100);
+                late.send(1, "late").unwrap();
+                Ok(())
+            });
+
+            loop {
+                match recv.read() {
+                    Ok(msg) => received_copy.lock().unwrap().push(msg),
+                    Err(mioco::mail::RecvError) => break,
+                }
+                if received_copy.lock().unwrap().len() == 3 {
+                    mioco::sleep(200);
+                }
+            }
+            Ok(())
+        });
+
+        assert_eq!(*received.lock().unwrap(),
+                   vec!["shutdown", "reload", "data1", "late", "data2", "data3"]);
+    }
+}
+
+#[test]
+fn mailbox_batches() {
+    for &threads in THREADS_N.iter() {
+        let finished_ok = Arc::new(Mutex::new(false));
+
+        let finished_copy = finished_ok.clone();
+        mioco::start_threads(threads, move || {
+            let (send, recv) = mioco::mail::mailbox();
+            assert!(recv.drain().is_empty());
+
+            send.send_batch(0..10).unwrap();
+            assert_eq!(recv.read_batch(4), Ok(vec![0, 1, 2, 3]));
+            assert_eq!(recv.drain(), (4..10).collect::<Vec<_>>());
+
+            mioco::spawn(move || {
+                mioco::sleep(50);
+                send.send_batch(vec![10, 11]).unwrap();
+                Ok(())
+            });
+            assert_eq!(recv.read_batch(100), Ok(vec![10, 11]));
+            assert_eq!(recv.read_batch(100), Err(mioco::mail::RecvError));
+
+            let (send, recv) = mioco::mail::mailbox();
+            drop(recv);
+            assert_eq!(send.send_batch(vec![1, 2]),
+                       Err(mioco::mail::SendError(vec![1, 2])));
+
+            *finished_copy.lock().unwrap() = true;
+            Ok(())
+        });
+
+        assert!(*finished_ok.lock().unwrap());
+    }
+}
+
+#[test]
+fn mailbox_concurrent_senders() {
+    const SENDERS: usize = 8;
+    const MESSAGES: usize = 10000;
+
+    for &threads in THREADS_N.iter() {
+        let finished_ok = Arc::new(Mutex::new(false));
+
+        let finished_copy = finished_ok.clone();
+        mioco::start_threads(threads, move || {
+            let (send, recv) = mioco::mail::mailbox();
+
+            for sender_i in 0..SENDERS {
+                let send = send.clone();
+                thread::spawn(move || {
+                    for i in 0..MESSAGES {
+                        send.send((sender_i, i)).unwrap();
+                    }
+                });
+            }
+            drop(send);
+
+            let mut next = vec![0; SENDERS];
+            while let Ok((sender_i, i)) = recv.read() {
+                assert_eq!(next[sender_i], i);
+                next[sender_i] += 1;
+            }
+            assert!(next.iter().all(|&n| n == MESSAGES));
+
+            *finished_copy.lock().unwrap() = true;
+            Ok(())
+        });
+
+        assert!(*finished_ok.lock().unwrap());
+    }
+}
+
+#[test]
+fn mailbox_iterators() {
+    let finished_ok = Arc::new(Mutex::new(false));
+
+    let finished_copy = finished_ok.clone();
+    mioco::test::Runtime::new(5).run(move || {
+        let (send, recv) = mioco::mail::mailbox();
+        send.send_batch(0..10).unwrap();
+        drop(send);
+        assert_eq!(recv.iter().filter(|i| i % 2 == 0).map(|i| i * 10).collect::<Vec<_>>(),
+                   vec![0, 20, 40, 60, 80]);
+
+        let (send1, recv1) = mioco::mail::mailbox();
+        let (send2, recv2) = mioco::mail::mailbox();
+        mioco::spawn(move || {
+            for i in 0..3 {
+                send1.send(i).unwrap();
+                mioco::sleep(10);
+                send2.send(10 + i).unwrap();
+            }
+            Ok(())
+        });
+        let mut merged: Vec<_> = recv1.merge(&recv2).collect();
+        merged.sort();
+        assert_eq!(merged, vec![0, 1, 2, 10, 11, 12]);
+
+        let (send, recv) = mioco::mail::mailbox();
+        mioco::spawn(move || {
+            send.send_batch(vec![1, 2, 3]).unwrap();
+            mioco::sleep(1000);
+            send.send(4).unwrap();
+            Ok(())
+        });
+        assert_eq!(recv.chunks(2, 100).collect::<Vec<_>>(),
+                   vec![vec![1, 2], vec![3], vec![4]]);
+
+        let (send, recv) = mioco::mail::mailbox();
+        mioco::spawn(move || {
+            send.send("a").unwrap();
+            mioco::sleep(250);
+            send.send("b").unwrap();
+            Ok(())
+        });
+        assert_eq!(recv.timeout(100).collect::<Vec<_>>(),
+                   vec![Ok("a"),
+                        Err(mioco::mail::RecvTimeoutError::Timeout),
+                        Err(mioco::mail::RecvTimeoutError::Timeout),
+                        Ok("b")]);
+
+        *finished_copy.lock().unwrap() = true;
+        Ok(())
+    });
+
+    assert!(*finished_ok.lock().unwrap());
+}
+
+#[test]
+fn rwlock_wait_queue_order() {
+    for &prefer_writers in [false, true].iter() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let order_copy = order.clone();
+        mioco::start_threads(1, move || {
+            let lock = Arc::new(if prefer_writers {
+                mioco::sync::RwLock::new_prefer_writers(())
+            } else {
+                mioco::sync::RwLock::new(())
+            });
+
+            let guard = lock.write().unwrap();
+            for &(name, write) in [("r1", false), ("w2", true), ("r3", false)].iter() {
+                let lock = lock.clone();
+                let order = order_copy.clone();
+                mioco::spawn(move || {
+                    if write {
+                        let _guard = lock.write().unwrap();
+                        order.lock().unwrap().push(name);
+                        mioco::sleep(20);
+                    } else {
+                        let _guard = lock.read().unwrap();
+                        order.lock().unwrap().push(name);
+                        mioco::sleep(20);
+                    }
+                    Ok(())
+                });
+                mioco::sleep(10);
+            }
+            assert!(lock.try_read().is_err());
+            drop(guard);
+            Ok(())
+        });
+
+        if prefer_writers {
+            assert_eq!(*order.lock().unwrap(), vec!["w2", "r1", "r3"]);
+        } else {
+            assert_eq!(*order.lock().unwrap(), vec!["r1", "w2", "r3"]);
+        }
+    }
+}
+
+#[test]
+fn sync_condvar_semaphore_barrier_waitgroup() {
+    use mioco::sync::{Barrier, Condvar, Semaphore, WaitGroup};
+
+    let finished_ok = Arc::new(Mutex::new(false));
+
+    let finished_copy = finished_ok.clone();
+    mioco::test::Runtime::new(11).run(move || {
+        // Semaphore caps concurrency
+        let semaphore = Arc::new(Semaphore::new(2));
+        let active = Arc::new(Mutex::new((0, 0)));
+        let wg = Arc::new(WaitGroup::new());
+        wg.add(5);
+        for _ in 0..5 {
+            let semaphore = semaphore.clone();
+            let active = active.clone();
+            let wg = wg.clone();
+            mioco::spawn(move || {
+                {
+                    let _permit = semaphore.acquire();
+                    {
+                        let mut active = active.lock().unwrap();
+                        active.0 += 1;
+                        active.1 = std::cmp::max(active.0, active.1);
+                    }
+                    mioco::sleep(10);
+                    active.lock().unwrap().0 -= 1;
+                }
+                wg.done();
+                Ok(())
+            });
+        }
+        mioco::sleep(1);
+        assert!(semaphore.try_acquire().is_none());
+        assert!(semaphore.acquire_timeout(5).is_none());
+        assert!(!wg.wait_timeout(5));
+        wg.wait();
+        assert_eq!(wg.count(), 0);
+        assert_eq!(active.lock().unwrap().1, 2);
+        assert_eq!(semaphore.available_permits(), 2);
+
+        // Condvar
+        let pair = Arc::new((mioco::sync::Mutex::new(false), Condvar::new()));
+        {
+            let &(ref lock, ref cvar) = &*pair;
+            let (guard, timed_out) = cvar.wait_timeout(lock.lock().unwrap(), 10).unwrap();
+            assert!(timed_out);
+            drop(guard);
+        }
+        let pair_copy = pair.clone();
+        mioco::spawn(move || {
+            mioco::sleep(100);
+            let &(ref lock, ref cvar) = &*pair_copy;
+            *lock.lock().unwrap() = true;
+            cvar.notify_all();
+            Ok(())
+        });
+        {
+            let &(ref lock, ref cvar) = &*pair;
+            let mut ready = lock.lock().unwrap();
+            while !*ready {
+                ready = cvar.wait(ready).unwrap();
+            }
+        }
+
+        // Barrier
+        let barrier = Arc::new(Barrier::new(3));
+        let leaders = Arc::new(Mutex::new(0));
+        for _ in 0..2 {
+            let barrier = barrier.clone();
+            let leaders = leaders.clone();
+            mioco::spawn(move || {
+                if barrier.wait().is_leader() {
+                    *leaders.lock().unwrap() += 1;
+                }
+                Ok(())
+            });
+        }
+        if barrier.wait().is_leader() {
+            *leaders.lock().unwrap() += 1;
+        }
+        mioco::sleep(1);
+        assert_eq!(*leaders.lock().unwrap(), 1);
+        assert!(Barrier::new(2).wait_timeout(10).is_none());
+
+        *finished_copy.lock().unwrap() = true;
+        Ok(())
+    });
+
+    assert!(*finished_ok.lock().unwrap());
+}
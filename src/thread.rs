@@ -1,6 +1,8 @@
 use std;
 use std::any::Any;
+use std::boxed::FnBox;
 use std::cell::{RefCell};
+use std::collections::HashMap;
 use std::rc::Rc;
 use std::sync::Arc;
 use std::panic;
@@ -9,10 +11,16 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 
 use super::coroutine::{self, Coroutine, CoroutineSlabHandle, RcCoroutine};
 use super::{SchedulerThread, token_to_ids, CoroutineControl};
+use super::trace::Tracer;
+use super::stats::Stats;
+use super::timer::{Clock, ScheduledRun};
+use super::fault::FaultInjector;
+use super::sim::SimHost;
 use super::mio_orig::{self, EventLoop, Token, EventSet};
 
 use slab;
 use context::Context;
+use time::SteadyTime;
 
 /// Current coroutine thread-local reference
 ///
@@ -35,18 +43,51 @@ pub struct HandlerThreadShared {
     coroutines_num: AtomicUsize,
     #[allow(dead_code)]
     thread_num: AtomicUsize,
+    stats: Arc<Stats>,
 }
 
 impl HandlerThreadShared {
-    pub fn new(thread_num: usize) -> Self {
+    pub fn new(thread_num: usize, stats: Arc<Stats>) -> Self {
         HandlerThreadShared {
             mioco_started: AtomicUsize::new(0),
             coroutines_num: AtomicUsize::new(0),
             thread_num: AtomicUsize::new(thread_num),
+            stats: stats,
         }
     }
 }
 
+/// Per-thread settings, copied from `Config` for every mioco thread
+#[derive(Clone)]
+pub struct ThreadConfig {
+    /// Default stack size
+    pub stack_size: usize,
+
+    /// Paint coroutine stacks to measure their usage
+    pub stack_usage_tracking: bool,
+
+    /// Measure coroutine run time and scheduling latency
+    pub timing_stats: bool,
+
+    /// Report coroutine stack overflows
+    pub overflow_reporting: bool,
+
+    /// Catch panics of the coroutines started on this thread
+    pub catch_panics: bool,
+
+    /// Coroutine lifecycle tracer
+    pub tracer: Option<Arc<Box<Tracer>>>,
+
+    /// Time source of timers
+    pub clock: Clock,
+
+    /// IO fault injection for new event sources
+    pub faults: Option<Arc<FaultInjector>>,
+
+    /// Simulated host new coroutines are placed on
+    pub sim_host: Option<SimHost>,
+}
+
 /// Data belonging to `Handler`, but referenced and manipulated by coroutinees
 /// belonging to it.
 pub struct HandlerShared {
@@ -62,8 +103,8 @@ pub struct HandlerShared {
     /// Shared between threads
     thread_shared: ArcHandlerThreadShared,
 
-    /// Default stack size
-    pub stack_size: usize,
+    /// Settings of this thread
+    pub config: ThreadConfig,
 
     /// Newly spawned Coroutines
     spawned: Vec<CoroutineControl>,
@@ -77,15 +118,15 @@ pub struct HandlerShared {
 impl HandlerShared {
     pub fn new(senders: Vec<MioSender>,
            thread_shared: ArcHandlerThreadShared,
-           stack_size: usize,
-           thread_id: usize)
+           thread_id: usize,
+           config: ThreadConfig)
            -> Self {
         HandlerShared {
             coroutines: slab::Slab::new(512),
             thread_shared: thread_shared,
             context: Context::empty(),
             senders: senders,
-            stack_size: stack_size,
+            config: config,
             spawned: Vec::new(),
             ready: Vec::new(),
             thread_id: thread_id,
@@ -100,6 +141,11 @@ impl HandlerShared {
         self.ready.push(coroutine_ctrl);
     }
 
+    /// Id of the thread
+    pub fn thread_id(&self) -> usize {
+        self.thread_id
+    }
+
     pub fn get_sender_to_own_thread(&self) -> MioSender {
         self.senders[self.thread_id].clone()
     }
@@ -135,6 +181,11 @@ impl HandlerShared {
         debug_assert!(prev > 0);
     }
 
+    /// Runtime statistics of the mioco instance
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.thread_shared.stats
+    }
+
     /// Get number of threads
     pub fn thread_num(&self) -> usize {
         self.thread_shared.thread_num.load(Ordering::Relaxed)
@@ -161,6 +212,8 @@ impl HandlerShared {
 pub struct Handler {
     shared: RcHandlerShared,
     scheduler: Box<SchedulerThread + 'static>,
+    /// Pending `Message::RunAt` functions, by id
+    scheduled_runs: HashMap<usize, ScheduledRun>,
 }
 
 impl Handler {
@@ -169,6 +222,7 @@ impl Handler {
         Handler {
             shared: shared,
             scheduler: scheduler,
+            scheduled_runs: HashMap::new(),
         }
     }
 
@@ -217,12 +271,33 @@ pub enum Message {
     Migration(CoroutineControl),
     /// Coroutine Panicked
     PropagatePanic(Box<Any + Send + 'static>),
+    /// Wake up the event loop, to have `SchedulerThread::tick()` called
+    Tick,
+    /// Virtual timer expired
+    Timeout(Token),
+    /// Event source not backed by a file descriptor is ready
+    EventSourceReady(Token, EventSet),
+    /// Run a function at given time, from the event loop; the id is for
+    /// `CancelRun`
+    RunAt(usize, SteadyTime, Box<FnBox() + Send + 'static>),
+    /// Virtual timer of `RunAt` expired
+    Run(usize, Box<FnBox() + Send + 'static>),
+    /// Cancel a function scheduled with `RunAt`, if not run yet
+    CancelRun(usize),
 }
 
 unsafe impl Send for Message {}
 
+/// EventLoop timeout type
+pub enum Timeout {
+    /// Readiness event for an event source
+    Event(Token),
+    /// Function scheduled with `Message::RunAt`
+    Run(usize, Box<FnBox() + Send + 'static>),
+}
+
 impl mio_orig::Handler for Handler {
-    type Timeout = Token;
+    type Timeout = Timeout;
     type Message = Message;
 
     fn tick(&mut self, event_loop: &mut mio_orig::EventLoop<Self>) {
@@ -270,10 +345,33 @@ impl mio_orig::Handler for Handler {
                 self.deliver_to_scheduler(event_loop);
             }
             Message::PropagatePanic(cause) => panic::propagate(cause),
+            Message::Tick => {}
+            Message::Timeout(token) => self.timeout(event_loop, token),
+            Message::EventSourceReady(token, events) => self.ready(event_loop, token, events),
+            Message::RunAt(id, deadline, f) => {
+                let clock = self.shared.borrow().config.clock.clone();
+                let run = clock.run_at(event_loop, deadline, id, f);
+                self.scheduled_runs.insert(id, run);
+            }
+            Message::Run(id, f) => {
+                self.scheduled_runs.remove(&id);
+                f()
+            }
+            Message::CancelRun(id) => {
+                if let Some(run) = self.scheduled_runs.remove(&id) {
+                    run.cancel(event_loop);
+                }
+            }
         }
     }
 
     fn timeout(&mut self, event_loop: &mut EventLoop<Self>, msg: Self::Timeout) {
-        self.ready(event_loop, msg, EventSet::readable());
+        match msg {
+            Timeout::Event(token) => self.ready(event_loop, token, EventSet::readable()),
+            Timeout::Run(id, f) => {
+                self.scheduled_runs.remove(&id);
+                f()
+            }
+        }
     }
 }
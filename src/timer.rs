@@ -1,9 +1,247 @@
-use super::{RW};
-use super::thread::Handler;
+use super::{RW, in_coroutine, tl_coroutine_current, sender_retry};
+use super::thread::{Handler, Message, MioSender, Timeout};
 use super::evented::{EventSourceTrait, RcEventSource, Evented, EventedImpl};
-use super::mio_orig::{EventLoop, Token, EventSet};
+use super::mio_orig::{self, EventLoop, Token, EventSet};
 use time::{SteadyTime, Duration};
 
+use spin::Mutex;
+use std::boxed::FnBox;
+use std::sync::Arc;
+
+/// Source of time for timers
+#[doc(hidden)]
+#[derive(Clone)]
+pub enum Clock {
+    /// Real, monotonic time
+    Real,
+    /// Virtual time (see `VirtualClock`)
+    Virtual(VirtualClock),
+}
+
+impl Clock {
+    /// Clock of the mioco instance the current coroutine is running in
+    pub fn current() -> Clock {
+        if in_coroutine() {
+            tl_coroutine_current().handler_shared().config.clock.clone()
+        } else {
+            Clock::Real
+        }
+    }
+
+    pub fn now(&self) -> SteadyTime {
+        match *self {
+            Clock::Real => SteadyTime::now(),
+            Clock::Virtual(ref clock) => clock.now(),
+        }
+    }
+
+    /// Deliver a timeout event for `token` at `deadline`.
+    pub fn wake_at(&self, event_loop: &mut EventLoop<Handler>, token: Token, deadline: SteadyTime) {
+        if let Clock::Virtual(ref clock) = *self {
+            trace!("Clock: set virtual timeout for {}", token.as_usize());
+            clock.register(deadline, Some(token), Message::Timeout(token), event_loop.channel());
+            return;
+        }
+
+        trace!("Clock: set timeout for {}", token.as_usize());
+        Clock::timeout_at(event_loop, Timeout::Event(token), deadline);
+    }
+
+    /// Run `f` on the event loop at `deadline`.
+    ///
+    /// `id` is passed back when `f` is due, with `Message::Run` or
+    /// `Timeout::Run`.
+    pub fn run_at(&self,
+                  event_loop: &mut EventLoop<Handler>,
+                  deadline: SteadyTime,
+                  id: usize,
+                  f: Box<FnBox() + Send + 'static>)
+                  -> ScheduledRun {
+        if let Clock::Virtual(ref clock) = *self {
+            clock.register(deadline, None, Message::Run(id, f), event_loop.channel());
+            return ScheduledRun::Virtual(clock.clone(), id);
+        }
+
+        ScheduledRun::Real(Clock::timeout_at(event_loop, Timeout::Run(id, f), deadline))
+    }
+
+    fn timeout_at(event_loop: &mut EventLoop<Handler>,
+                  timeout: Timeout,
+                  deadline: SteadyTime)
+                  -> mio_orig::Timeout {
+        let now = SteadyTime::now();
+        let delay = if deadline <= now {
+            0
+        } else {
+            (deadline - now).num_milliseconds()
+        };
+
+        match event_loop.timeout_ms(timeout, delay as u64) {
+            Ok(timeout) => timeout,
+            Err(reason) => {
+                panic!("Could not create mio::Timeout: {:?}", reason);
+            }
+        }
+    }
+
+    /// Cancel timeout set with `wake_at()`, if possible.
+    ///
+    /// Real timeouts are not cancelled: they will result in a spurious
+    /// event.
+    pub fn cancel(&self, token: Token) {
+        if let Clock::Virtual(ref clock) = *self {
+            clock.deregister(token);
+        }
+    }
+}
+
+/// Function scheduled with `Clock::run_at()`
+#[doc(hidden)]
+pub enum ScheduledRun {
+    /// Event loop timeout
+    Real(mio_orig::Timeout),
+    /// Virtual timer, by id
+    Virtual(VirtualClock, usize),
+}
+
+impl ScheduledRun {
+    /// Cancel the function, dropping it.
+    pub fn cancel(self, event_loop: &mut EventLoop<Handler>) {
+        match self {
+            ScheduledRun::Real(timeout) => {
+                event_loop.clear_timeout(timeout);
+            }
+            ScheduledRun::Virtual(clock, id) => clock.deregister_run(id),
+        }
+    }
+}
+
+/// Current time, as seen by mioco timers
+///
+/// Use it as a base for `Timer::set_timeout_absolute()`. Inside a mioco
+/// instance using a `VirtualClock` this returns virtual time.
+pub fn now() -> SteadyTime {
+    Clock::current().now()
+}
+
+/// Virtual time source for timers
+///
+/// Timers of a mioco instance using a virtual clock (see
+/// `Config::set_virtual_clock()`) expire only when the clock is moved
+/// forward: explicitly with `advance()` or, in `mioco::test::Runtime`,
+/// automatically when all coroutines are blocked, straight to the nearest
+/// timer deadline. This makes tests of timeouts fast and independent of
+/// machine load.
+///
+/// Meant for single-threaded instances. `VirtualClock` is a cheaply
+/// cloneable handle.
+#[derive(Clone)]
+pub struct VirtualClock {
+    shared: Arc<Mutex<VirtualClockShared>>,
+}
+
+struct VirtualClockShared {
+    now: SteadyTime,
+    timers: Vec<VirtualTimer>,
+}
+
+struct VirtualTimer {
+    deadline: SteadyTime,
+    /// Token to cancel the timer with, if any
+    token: Option<Token>,
+    message: Message,
+    sender: MioSender,
+}
+
+impl VirtualClock {
+    /// Create a new virtual clock, starting at current real time.
+    pub fn new() -> Self {
+        VirtualClock {
+            shared: Arc::new(Mutex::new(VirtualClockShared {
+                now: SteadyTime::now(),
+                timers: Vec::new(),
+            })),
+        }
+    }
+
+    /// Current virtual time.
+    pub fn now(&self) -> SteadyTime {
+        self.shared.lock().now
+    }
+
+    /// Move the clock forward by `delay_ms`, firing expired timers.
+    pub fn advance(&self, delay_ms: i64) {
+        let mut lock = self.shared.lock();
+        lock.now = lock.now + Duration::milliseconds(delay_ms);
+        lock.fire_expired();
+    }
+
+    /// Move the clock forward to the nearest timer deadline and fire it.
+    ///
+    /// Returns `false` if there are no timers pending.
+    pub fn advance_to_next(&self) -> bool {
+        let mut lock = self.shared.lock();
+        let next = match lock.timers.iter().map(|t| t.deadline).min() {
+            Some(next) => next,
+            None => return false,
+        };
+        if next > lock.now {
+            lock.now = next;
+        }
+        lock.fire_expired();
+        true
+    }
+
+    /// Are there any timers waiting for the clock to advance?
+    pub fn has_timers(&self) -> bool {
+        !self.shared.lock().timers.is_empty()
+    }
+
+    fn register(&self,
+                deadline: SteadyTime,
+                token: Option<Token>,
+                message: Message,
+                sender: MioSender) {
+        let mut lock = self.shared.lock();
+        lock.timers.push(VirtualTimer {
+            deadline: deadline,
+            token: token,
+            message: message,
+            sender: sender,
+        });
+        lock.fire_expired();
+    }
+
+    fn deregister(&self, token: Token) {
+        self.shared.lock().timers.retain(|t| t.token != Some(token));
+    }
+
+    fn deregister_run(&self, id: usize) {
+        self.shared.lock().timers.retain(|t| {
+            match t.message {
+                Message::Run(run_id, _) => run_id != id,
+                _ => true,
+            }
+        });
+    }
+}
+
+impl VirtualClockShared {
+    fn fire_expired(&mut self) {
+        let now = self.now;
+        let mut i = 0;
+        while i < self.timers.len() {
+            if self.timers[i].deadline <= now {
+                let timer = self.timers.swap_remove(i);
+                trace!("VirtualClock: firing timer {:?}", timer.token);
+                sender_retry(&timer.sender, timer.message);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
 /// A Timer generating event after a given time
 ///
 /// Can be used to block coroutine or to implement timeout for other `EventSource`.
@@ -18,12 +256,17 @@ pub struct Timer {
 
 struct TimerCore {
     timeout: SteadyTime,
+    clock: Clock,
 }
 
 impl Timer {
     /// Create a new timer
     pub fn new() -> Timer {
-        let timer_core = TimerCore { timeout: SteadyTime::now() };
+        let clock = Clock::current();
+        let timer_core = TimerCore {
+            timeout: clock.now(),
+            clock: clock,
+        };
         Timer { rc: RcEventSource::new(timer_core) }
     }
 
@@ -63,7 +306,7 @@ impl Timer {
         let done = self.is_done();
 
         if done {
-            Some(SteadyTime::now())
+            Some(self.rc.io_ref().clock.now())
         } else {
             None
         }
@@ -73,10 +316,13 @@ impl Timer {
     ///
     /// The timeout counts from the time `set_timeout` is called.
     pub fn set_timeout(&mut self, delay_ms: i64) {
-        self.rc.io_mut().timeout = SteadyTime::now() + Duration::milliseconds(delay_ms);
+        let now = self.rc.io_ref().clock.now();
+        self.rc.io_mut().timeout = now + Duration::milliseconds(delay_ms);
     }
 
     /// Set timeout for the timer using absolute time.
+    ///
+    /// See `timer::now()`.
     pub fn set_timeout_absolute(&mut self, timeout: SteadyTime) {
         self.rc.io_mut().timeout = timeout;
     }
@@ -90,33 +336,21 @@ impl Timer {
 
 impl EventSourceTrait for TimerCore {
     fn register(&self, event_loop: &mut EventLoop<Handler>, token: Token, _interest: EventSet) {
-        let timeout = self.timeout;
-        let now = SteadyTime::now();
-        let delay = if timeout <= now {
-            0
-        } else {
-            (timeout - now).num_milliseconds()
-        };
-
-        trace!("Timer({}): set timeout in {}ms", token.as_usize(), delay);
-        match event_loop.timeout_ms(token, delay as u64) {
-            Ok(_) => {}
-            Err(reason) => {
-                panic!("Could not create mio::Timeout: {:?}", reason);
-            }
-        }
+        self.clock.wake_at(event_loop, token, self.timeout);
     }
 
     fn reregister(&self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
         self.register(event_loop, token, interest)
     }
 
-    fn deregister(&self, _event_loop: &mut EventLoop<Handler>, _token: Token) {}
+    fn deregister(&self, _event_loop: &mut EventLoop<Handler>, token: Token) {
+        self.clock.cancel(token);
+    }
 
     fn should_resume(&self) -> bool {
-        trace!("Timer: should_resume? {}",
-               self.timeout <= SteadyTime::now());
-        self.timeout <= SteadyTime::now()
+        let now = self.clock.now();
+        trace!("Timer: should_resume? {}", self.timeout <= now);
+        self.timeout <= now
     }
 }
 
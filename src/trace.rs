@@ -0,0 +1,319 @@
+use super::{EventSourceId, RW};
+use super::ExitStatus;
+
+use time::SteadyTime;
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Details of a coroutine lifecycle event
+#[derive(Copy, Clone, Debug)]
+pub struct Info {
+    /// Id of the coroutine
+    ///
+    /// Unlike internal slab ids it's unique within the process and does
+    /// not change when the coroutine migrates between threads.
+    pub coroutine_id: usize,
+    /// Id of the mioco thread the event happened on
+    pub thread_id: usize,
+    /// Time of the event
+    pub time: SteadyTime,
+}
+
+/// Coroutine lifecycle tracer
+///
+/// Install with `Config::set_tracer()`. Callbacks are called
+/// synchronously on the mioco thread the event happened on, so they should
+/// be cheap and must not block. All of them default to doing nothing.
+pub trait Tracer : Send + Sync {
+    /// Coroutine was spawned.
+    fn spawned(&self, _info: Info) {}
+
+    /// Coroutine is about to be resumed by the scheduler.
+    fn resumed(&self, _info: Info) {}
+
+    /// Coroutine is blocking on a given set of event sources.
+    fn blocked(&self, _info: Info, _on: &[(EventSourceId, RW)]) {}
+
+    /// Coroutine yielded (see `yield_now()`).
+    fn yielded(&self, _info: Info) {}
+
+    /// Coroutine is being migrated to a thread `to_thread_id`.
+    fn migrated(&self, _info: Info, _to_thread_id: usize) {}
+
+    /// Coroutine finished.
+    fn finished(&self, _info: Info, _status: &ExitStatus) {}
+
+    /// Mioco instance finished; called when `Mioco::start()` is about to
+    /// return.
+    fn stopped(&self) {}
+}
+
+/// Chrome `trace_event` exporter
+///
+/// Records when coroutines were running (per mioco thread) and what they
+/// were waiting on in between, keeping only `capacity` most recent spans
+/// of every mioco thread in memory. Each thread records into its own
+/// buffer, so threads don't contend on recording.
+///
+/// Spans can be written out at any time using `write_json()`, or
+/// automatically when `Mioco::start()` returns (see `write_on_stop()`).
+/// The output can be loaded in `chrome://tracing` or Perfetto.
+///
+/// `ChromeTracer` is a cheaply cloneable handle, so keep a clone around
+/// after passing one to `Config::set_tracer()`.
+#[derive(Clone)]
+pub struct ChromeTracer {
+    shared: Arc<ChromeTracerShared>,
+}
+
+struct ChromeTracerShared {
+    epoch: SteadyTime,
+    capacity: usize,
+    /// Span buffers, by mioco thread id
+    threads: RwLock<Vec<Mutex<ThreadSpans>>>,
+    output: Mutex<Option<PathBuf>>,
+}
+
+/// Spans recorded on one mioco thread
+///
+/// A coroutine's events between being resumed and migrating away all
+/// happen on one thread, so its open span is always in that thread's
+/// buffer.
+struct ThreadSpans {
+    spans: VecDeque<Span>,
+    /// Spans not finished yet, by coroutine id
+    open: HashMap<usize, Span>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum SpanKind {
+    Run,
+    Blocked,
+    Yielded,
+}
+
+struct Span {
+    kind: SpanKind,
+    coroutine_id: usize,
+    thread_id: usize,
+    start: SteadyTime,
+    end: SteadyTime,
+    blocked_on: Vec<(EventSourceId, RW)>,
+}
+
+impl Span {
+    fn new(kind: SpanKind, info: Info) -> Self {
+        Span {
+            kind: kind,
+            coroutine_id: info.coroutine_id,
+            thread_id: info.thread_id,
+            start: info.time,
+            end: info.time,
+            blocked_on: Vec::new(),
+        }
+    }
+}
+
+impl ChromeTracer {
+    /// Create a tracer keeping at most `capacity` spans.
+    pub fn new(capacity: usize) -> Self {
+        ChromeTracer {
+            shared: Arc::new(ChromeTracerShared {
+                epoch: SteadyTime::now(),
+                capacity: capacity,
+                threads: RwLock::new(Vec::new()),
+                output: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Write recorded spans to `path` when `Mioco::start()` returns.
+    pub fn write_on_stop<P: AsRef<Path>>(&self, path: P) -> &Self {
+        *self.shared.output.lock().unwrap() = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Write recorded spans as Chrome `trace_event` JSON.
+    ///
+    /// Spans of all threads are merged in order of their start. Spans that
+    /// are still open are not included.
+    pub fn write_json<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        self.shared.write_json(w)
+    }
+
+    fn open(&self, kind: SpanKind, info: Info) {
+        self.open_span(Span::new(kind, info), info);
+    }
+
+    fn open_span(&self, span: Span, info: Info) {
+        let capacity = self.shared.capacity;
+        self.shared.with_thread(info.thread_id, move |thread| {
+            thread.close(info, capacity);
+            thread.open.insert(info.coroutine_id, span);
+        });
+    }
+
+    fn close(&self, info: Info) {
+        let capacity = self.shared.capacity;
+        self.shared.with_thread(info.thread_id, |thread| thread.close(info, capacity));
+    }
+}
+
+impl ThreadSpans {
+    fn new() -> Self {
+        ThreadSpans {
+            spans: VecDeque::new(),
+            open: HashMap::new(),
+        }
+    }
+
+    fn close(&mut self, info: Info, capacity: usize) {
+        if let Some(mut span) = self.open.remove(&info.coroutine_id) {
+            span.end = info.time;
+            if capacity == 0 {
+                return;
+            }
+            if self.spans.len() == capacity {
+                self.spans.pop_front();
+            }
+            self.spans.push_back(span);
+        }
+    }
+}
+
+impl ChromeTracerShared {
+    /// Run `f` on the span buffer of a given thread.
+    ///
+    /// Buffers are only added under the write lock, the first time a
+    /// thread records anything.
+    fn with_thread<F>(&self, thread_id: usize, f: F)
+        where F: FnOnce(&mut ThreadSpans)
+    {
+        {
+            let threads = self.threads.read().unwrap();
+            if let Some(thread) = threads.get(thread_id) {
+                f(&mut *thread.lock().unwrap());
+                return;
+            }
+        }
+
+        let mut threads = self.threads.write().unwrap();
+        while threads.len() <= thread_id {
+            threads.push(Mutex::new(ThreadSpans::new()));
+        }
+        f(&mut *threads[thread_id].lock().unwrap());
+    }
+
+    fn micros(&self, time: SteadyTime) -> i64 {
+        (time - self.epoch).num_microseconds().unwrap_or(0)
+    }
+
+    fn write_json<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        let threads = self.threads.read().unwrap();
+        let threads = threads.iter().map(|thread| thread.lock().unwrap()).collect::<Vec<_>>();
+        let mut spans = threads.iter().flat_map(|thread| thread.spans.iter()).collect::<Vec<_>>();
+        spans.sort_by(|a, b| a.start.cmp(&b.start));
+
+        try!(write!(w, "{{\"traceEvents\":["));
+        let mut first = true;
+        for span in spans {
+            if !first {
+                try!(write!(w, ","));
+            }
+            first = false;
+
+            let ts = self.micros(span.start);
+            let dur = self.micros(span.end) - ts;
+            match span.kind {
+                SpanKind::Run => {
+                    try!(write!(w,
+                                "{{\"name\":\"coroutine {}\",\"cat\":\"run\",\"ph\":\"X\",\
+                                 \"ts\":{},\"dur\":{},\"pid\":1,\"tid\":{},\
+                                 \"args\":{{\"coroutine\":{}}}}}",
+                                span.coroutine_id,
+                                ts,
+                                dur,
+                                span.thread_id,
+                                span.coroutine_id));
+                }
+                SpanKind::Blocked | SpanKind::Yielded => {
+                    let name = if span.kind == SpanKind::Blocked {
+                        "blocked"
+                    } else {
+                        "yielded"
+                    };
+                    let on = span.blocked_on
+                                 .iter()
+                                 .map(|&(id, rw)| {
+                                     format!("{}:{}",
+                                             id.as_usize(),
+                                             match rw.as_tuple() {
+                                                 (true, true) => "rw",
+                                                 (true, false) => "r",
+                                                 (false, true) => "w",
+                                                 (false, false) => "-",
+                                             })
+                                 })
+                                 .collect::<Vec<_>>()
+                                 .join(" ");
+                    try!(write!(w,
+                                "{{\"name\":\"{}\",\"cat\":\"wait\",\"ph\":\"b\",\"ts\":{},\
+                                 \"id\":{},\"pid\":1,\"tid\":{},\
+                                 \"args\":{{\"coroutine\":{},\"on\":\"{}\"}}}},",
+                                name,
+                                ts,
+                                span.coroutine_id,
+                                span.thread_id,
+                                span.coroutine_id,
+                                on));
+                    try!(write!(w,
+                                "{{\"name\":\"{}\",\"cat\":\"wait\",\"ph\":\"e\",\"ts\":{},\
+                                 \"id\":{},\"pid\":1,\"tid\":{}}}",
+                                name,
+                                ts + dur,
+                                span.coroutine_id,
+                                span.thread_id));
+                }
+            }
+        }
+        write!(w, "]}}")
+    }
+}
+
+impl Tracer for ChromeTracer {
+    fn resumed(&self, info: Info) {
+        self.open(SpanKind::Run, info);
+    }
+
+    fn blocked(&self, info: Info, on: &[(EventSourceId, RW)]) {
+        let mut span = Span::new(SpanKind::Blocked, info);
+        span.blocked_on.extend_from_slice(on);
+        self.open_span(span, info);
+    }
+
+    fn yielded(&self, info: Info) {
+        self.open(SpanKind::Yielded, info);
+    }
+
+    fn migrated(&self, info: Info, _to_thread_id: usize) {
+        self.close(info);
+    }
+
+    fn finished(&self, info: Info, _status: &ExitStatus) {
+        self.close(info);
+    }
+
+    fn stopped(&self) {
+        let output = self.shared.output.lock().unwrap();
+        if let Some(ref path) = *output {
+            let res = File::create(path).and_then(|mut file| self.write_json(&mut file));
+            if let Err(err) = res {
+                warn!("ChromeTracer: couldn't write {}: {}", path.display(), err);
+            }
+        }
+    }
+}
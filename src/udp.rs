@@ -1,5 +1,9 @@
 use super::RW;
 use super::evented::{EventedImpl, MioAdapter};
+#[cfg(feature = "sim")]
+use super::evented::{Backend, sim_unsupported};
+#[cfg(feature = "sim")]
+use super::sim::{self, SimUdpSocket};
 use super::mio_orig;
 use std::io;
 use std::net::SocketAddr;
@@ -7,8 +11,49 @@ use std::net::SocketAddr;
 pub use mio_orig::IpAddr;
 
 /// Udp Socket
+#[cfg(not(feature = "sim"))]
 pub type UdpSocket = MioAdapter<mio_orig::udp::UdpSocket>;
 
+/// Udp Socket
+///
+/// Simulated when created by a coroutine placed on a simulated host (see
+/// `sim`).
+#[cfg(feature = "sim")]
+pub type UdpSocket = MioAdapter<Backend<mio_orig::udp::UdpSocket, SimUdpSocket>>;
+
+impl UdpSocket {
+    /// Block on read.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let res = self.try_read(buf);
+
+            match res {
+                Ok(None) => self.block_on_prv(RW::read()),
+                Ok(Some(r)) => {
+                    return Ok(r);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Block on write.
+    pub fn write(&mut self, buf: &[u8], target: &SocketAddr) -> io::Result<usize> {
+        loop {
+            let res = self.try_write(buf, target);
+
+            match res {
+                Ok(None) => self.block_on_prv(RW::write()),
+                Ok(Some(r)) => {
+                    return Ok(r);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "sim"))]
 impl UdpSocket {
     /// Return a new unbound IPv4 UDP Socket.
     pub fn v4() -> io::Result<Self> {
@@ -41,21 +86,6 @@ impl UdpSocket {
         self.shared().io_ref().try_clone().map(|t| MioAdapter::new(t))
     }
 
-    /// Block on read.
-    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        loop {
-            let res = self.try_read(buf);
-
-            match res {
-                Ok(None) => self.block_on_prv(RW::read()),
-                Ok(Some(r)) => {
-                    return Ok(r);
-                }
-                Err(e) => return Err(e),
-            }
-        }
-    }
-
     /// Try reading data into a buffer.
     ///
     /// This will not block.
@@ -63,21 +93,6 @@ impl UdpSocket {
         self.shared().io_ref().recv_from(buf)
     }
 
-    /// Block on write.
-    pub fn write(&mut self, buf: &[u8], target: &SocketAddr) -> io::Result<usize> {
-        loop {
-            let res = self.try_write(buf, target);
-
-            match res {
-                Ok(None) => self.block_on_prv(RW::write()),
-                Ok(Some(r)) => {
-                    return Ok(r);
-                }
-                Err(e) => return Err(e),
-            }
-        }
-    }
-
     /// Try writing a data from the buffer.
     ///
     /// This will not block.
@@ -110,3 +125,120 @@ impl UdpSocket {
         self.shared().io_ref().set_multicast_time_to_live(ttl)
     }
 }
+
+#[cfg(feature = "sim")]
+impl UdpSocket {
+    /// Return a new unbound IPv4 UDP Socket.
+    pub fn v4() -> io::Result<Self> {
+        match sim::current_host() {
+            Some(host) => Ok(MioAdapter::new(Backend::Sim(host.udp_socket()))),
+            None => mio_orig::udp::UdpSocket::v4().map(|t| MioAdapter::new(Backend::Mio(t))),
+        }
+    }
+
+    /// Return a new unbound IPv6 UDP Socket.
+    pub fn v6() -> io::Result<Self> {
+        match sim::current_host() {
+            Some(host) => Ok(MioAdapter::new(Backend::Sim(host.udp_socket()))),
+            None => mio_orig::udp::UdpSocket::v6().map(|t| MioAdapter::new(Backend::Mio(t))),
+        }
+    }
+
+    /// Return a new bound UDP Socket.
+    pub fn bound(addr: &SocketAddr) -> io::Result<Self> {
+        match sim::current_host() {
+            Some(host) => {
+                let mut socket = host.udp_socket();
+                try!(socket.bind(addr));
+                Ok(MioAdapter::new(Backend::Sim(socket)))
+            }
+            None => {
+                mio_orig::udp::UdpSocket::bound(addr).map(|t| MioAdapter::new(Backend::Mio(t)))
+            }
+        }
+    }
+
+    /// Bind the unbound UDP Socket.
+    pub fn bind(&self, addr: &SocketAddr) -> io::Result<()> {
+        match *self.shared().io_mut() {
+            Backend::Mio(ref io) => io.bind(addr),
+            Backend::Sim(ref mut io) => io.bind(addr),
+        }
+    }
+
+    /// Local address of the Socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.local_addr(),
+            Backend::Sim(ref io) => io.local_addr(),
+        }
+    }
+
+    /// Try cloning the socket.
+    pub fn try_clone(&self) -> io::Result<UdpSocket> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.try_clone().map(|t| MioAdapter::new(Backend::Mio(t))),
+            Backend::Sim(_) => Err(sim_unsupported()),
+        }
+    }
+
+    /// Try reading data into a buffer.
+    ///
+    /// This will not block.
+    pub fn try_read(&mut self, buf: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.recv_from(buf),
+            Backend::Sim(ref io) => io.recv_from(buf),
+        }
+    }
+
+    /// Try writing a data from the buffer.
+    ///
+    /// This will not block.
+    pub fn try_write(&self, buf: &[u8], target: &SocketAddr) -> io::Result<Option<usize>> {
+        match *self.shared().io_mut() {
+            Backend::Mio(ref io) => io.send_to(buf, target),
+            Backend::Sim(ref mut io) => io.send_to(buf, target),
+        }
+    }
+
+    /// Set broadcast flag.
+    pub fn set_broadcast(&self, on: bool) -> io::Result<()> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.set_broadcast(on),
+            Backend::Sim(_) => Ok(()),
+        }
+    }
+
+    /// Set multicast loop flag.
+    pub fn set_multicast_loop(&self, on: bool) -> io::Result<()> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.set_multicast_loop(on),
+            Backend::Sim(_) => Ok(()),
+        }
+    }
+
+    /// Join multicast.
+    pub fn join_multicast(&self, multi: &IpAddr) -> io::Result<()> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.join_multicast(multi),
+            Backend::Sim(_) => Err(sim_unsupported()),
+        }
+    }
+
+    /// Leave multicast.
+    pub fn leave_multicast(&self, multi: &IpAddr) -> io::Result<()> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.leave_multicast(multi),
+            Backend::Sim(_) => Err(sim_unsupported()),
+        }
+    }
+
+    /// Set multicast TTL.
+    pub fn set_multicast_time_to_live(&self, ttl: i32) -> io::Result<()> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.set_multicast_time_to_live(ttl),
+            Backend::Sim(_) => Ok(()),
+        }
+    }
+}
@@ -1,5 +1,9 @@
 use super::{RW,};
 use super::evented::{Evented, EventedImpl, MioAdapter};
+#[cfg(feature = "sim")]
+use super::evented::{Backend, sim_unsupported};
+#[cfg(feature = "sim")]
+use super::sim::{self, SimListener, SimStream};
 use std::io;
 use super::mio_orig;
 use std::path::Path;
@@ -12,8 +16,10 @@ pub type PipeReader = MioAdapter<mio_orig::unix::PipeReader>;
 pub type PipeWriter = MioAdapter<mio_orig::unix::PipeWriter>;
 
 /// Unix listener
+#[cfg(not(feature = "sim"))]
 pub type UnixListener = MioAdapter<mio_orig::unix::UnixListener>;
 
+#[cfg(not(feature = "sim"))]
 impl UnixListener {
     /// Bind to a path
     pub fn bind(addr: &Path) -> io::Result<Self> {
@@ -22,6 +28,27 @@ impl UnixListener {
  
 }
 
+/// Unix listener
+///
+/// Simulated when created by a coroutine placed on a simulated host (see
+/// `sim`); paths are then local to that host.
+#[cfg(feature = "sim")]
+pub type UnixListener = MioAdapter<Backend<mio_orig::unix::UnixListener, SimListener>>;
+
+#[cfg(feature = "sim")]
+impl UnixListener {
+    /// Bind to a path
+    pub fn bind(addr: &Path) -> io::Result<Self> {
+        match sim::current_host() {
+            Some(host) => host.unix_listen(addr).map(|t| MioAdapter::new(Backend::Sim(t))),
+            None => {
+                mio_orig::unix::UnixListener::bind(addr).map(|t| MioAdapter::new(Backend::Mio(t)))
+            }
+        }
+    }
+ 
+}
+
 /// Unix socket
 pub type UnixSocket = MioAdapter<mio_orig::unix::UnixSocket>;
 
@@ -37,7 +64,7 @@ impl UnixSocket {
             .io_ref()
             .try_clone()
             .and_then(|t| mio_orig::unix::UnixSocket::connect(t, addr))
-            .map(|(t, b)| (MioAdapter::new(t), b))
+            .map(|(t, b)| (unix_stream(t), b))
     }
 
     /// Bind the socket to the specified address
@@ -52,9 +79,28 @@ impl UnixSocket {
 }
 
 /// Unix stream
+#[cfg(not(feature = "sim"))]
 pub type UnixStream = MioAdapter<mio_orig::unix::UnixStream>;
 
+/// Unix stream
+///
+/// Simulated when created by a coroutine placed on a simulated host (see
+/// `sim`), or accepted from a simulated `UnixListener`. Simulated streams
+/// can't pass file descriptors.
+#[cfg(feature = "sim")]
+pub type UnixStream = MioAdapter<Backend<mio_orig::unix::UnixStream, SimStream>>;
+
+#[cfg(not(feature = "sim"))]
+fn unix_stream(t: mio_orig::unix::UnixStream) -> UnixStream {
+    MioAdapter::new(t)
+}
+
+#[cfg(feature = "sim")]
+fn unix_stream(t: mio_orig::unix::UnixStream) -> UnixStream {
+    MioAdapter::new(Backend::Mio(t))
+}
 
+#[cfg(not(feature = "sim"))]
 impl UnixStream {
     /// Connect UnixStream to `path`
     pub fn connect<P: AsRef<Path> + ?Sized>(path: &P) -> io::Result<UnixStream> {
@@ -75,6 +121,58 @@ impl UnixStream {
         self.shared().io_mut().try_read_recv_fd(buf)
     }
 
+    /// Try writing a data from the buffer.
+    ///
+    /// This will not block.
+    pub fn try_write_send_fd(&self, buf: &[u8], fd: RawFd) -> io::Result<Option<usize>> {
+        self.shared().io_mut().try_write_send_fd(buf, fd)
+    }
+}
+
+#[cfg(feature = "sim")]
+impl UnixStream {
+    /// Connect UnixStream to `path`
+    pub fn connect<P: AsRef<Path> + ?Sized>(path: &P) -> io::Result<UnixStream> {
+        match sim::current_host() {
+            Some(host) => host.unix_connect(path.as_ref()).map(|t| MioAdapter::new(Backend::Sim(t))),
+            None => {
+                mio_orig::unix::UnixStream::connect(path).map(|t| MioAdapter::new(Backend::Mio(t)))
+            }
+        }
+    }
+
+    /// Clone
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match *self.shared().io_ref() {
+            Backend::Mio(ref io) => io.try_clone().map(|t| MioAdapter::new(Backend::Mio(t))),
+            Backend::Sim(_) => Err(sim_unsupported()),
+        }
+    }
+
+    /// Try reading data into a buffer.
+    ///
+    /// This will not block.
+    pub fn try_read_recv_fd(&mut self,
+                            buf: &mut [u8])
+                            -> io::Result<Option<(usize, Option<RawFd>)>> {
+        match *self.shared().io_mut() {
+            Backend::Mio(ref mut io) => io.try_read_recv_fd(buf),
+            Backend::Sim(_) => Err(sim_unsupported()),
+        }
+    }
+
+    /// Try writing a data from the buffer.
+    ///
+    /// This will not block.
+    pub fn try_write_send_fd(&self, buf: &[u8], fd: RawFd) -> io::Result<Option<usize>> {
+        match *self.shared().io_mut() {
+            Backend::Mio(ref mut io) => io.try_write_send_fd(buf, fd),
+            Backend::Sim(_) => Err(sim_unsupported()),
+        }
+    }
+}
+
+impl UnixStream {
     /// Block on read.
     pub fn read_recv_fd(&mut self, buf: &mut [u8]) -> io::Result<(usize, Option<RawFd>)> {
         loop {
@@ -90,13 +188,6 @@ impl UnixStream {
         }
     }
 
-    /// Try writing a data from the buffer.
-    ///
-    /// This will not block.
-    pub fn try_write_send_fd(&self, buf: &[u8], fd: RawFd) -> io::Result<Option<usize>> {
-        self.shared().io_mut().try_write_send_fd(buf, fd)
-    }
-
     /// Block on write
     pub fn write_send_fd(&mut self, buf: &[u8], fd: RawFd) -> io::Result<usize> {
         loop {
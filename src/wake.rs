@@ -0,0 +1,82 @@
+use super::thread::{Handler, Message, MioSender};
+use super::mio_orig::{EventLoop, Token, EventSet};
+use super::sender_retry;
+
+/// Registration of a coroutine blocked on an event source that is not
+/// backed by a file descriptor
+///
+/// Such event sources keep a `Waker` next to their state, register it
+/// from `EventSourceTrait::register()` and call `wake()` whenever they
+/// might have become ready. The coroutine is notified at most once per
+/// registration.
+pub struct Waker {
+    token: Option<Token>,
+    sender: Option<MioSender>,
+    interest: EventSet,
+}
+
+impl Waker {
+    pub fn new() -> Self {
+        Waker {
+            token: None,
+            sender: None,
+            interest: EventSet::none(),
+        }
+    }
+
+    pub fn register(&mut self, event_loop: &mut EventLoop<Handler>, token: Token, interest: EventSet) {
+        self.token = Some(token);
+        self.sender = Some(event_loop.channel());
+        self.interest = interest;
+    }
+
+    pub fn deregister(&mut self) {
+        self.token = None;
+        self.sender = None;
+        self.interest = EventSet::none();
+    }
+
+    /// Notify registered coroutine, if it is interested in any of `events`.
+    pub fn wake(&mut self, events: EventSet) {
+        if let Some(notification) = self.take_notification(events) {
+            notification.send();
+        }
+    }
+
+    /// Like `wake()`, but return the notification instead of sending it.
+    ///
+    /// Sending can spin until the coroutine's event loop has room for it,
+    /// so send it only after releasing locks that loop might need.
+    pub fn take_notification(&mut self, events: EventSet) -> Option<Notification> {
+        let events = events & self.interest;
+        if events.is_none() {
+            return None;
+        }
+
+        match (self.token, self.sender.as_ref()) {
+            (Some(token), Some(sender)) => {
+                self.interest = EventSet::none();
+                Some(Notification {
+                    token: token,
+                    sender: sender.clone(),
+                    events: events,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Notification taken from a `Waker`, not sent yet
+pub struct Notification {
+    token: Token,
+    sender: MioSender,
+    events: EventSet,
+}
+
+impl Notification {
+    pub fn send(self) {
+        trace!("Waker: notifying {:?} about {:?}", self.token, self.events);
+        sender_retry(&self.sender, Message::EventSourceReady(self.token, self.events));
+    }
+}